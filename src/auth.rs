@@ -0,0 +1,394 @@
+use std::collections::HashMap;
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Path, Query},
+    http::request::Parts,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::{
+    config::Config,
+    error::Error,
+    filter::retain_groups,
+    proxy::Proxy,
+    state::AppState,
+    util::{decode_base64, now},
+};
+
+/// Someone allowed to fetch subscriptions, identified by a secret token.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct User {
+    pub token: String,
+    /// Groups this user may use, all of them when not set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub groups: Option<Vec<String>>,
+}
+
+impl User {
+    pub fn allows(&self, group: &str) -> bool {
+        self.groups
+            .as_ref()
+            .is_none_or(|groups| groups.iter().any(|g| g == group))
+    }
+
+    /// Keep the groups this user may use.
+    pub fn filter(&self, proxies: HashMap<String, Vec<Proxy>>) -> HashMap<String, Vec<Proxy>> {
+        retain_groups(proxies, |group| self.allows(group))
+    }
+}
+
+impl Config {
+    /// The user `token` belongs to, or `None` when no users are configured and
+    /// subscriptions are open to anyone.
+    pub fn authorize(&self, token: Option<&str>) -> Result<Option<&User>, Error> {
+        if self.users.is_empty() {
+            return Ok(None);
+        }
+
+        let token = token.ok_or(Error::Unauthorized)?;
+        self.users
+            .values()
+            .find(|user| constant_time_eq(user.token.as_bytes(), token.as_bytes()))
+            .map(Some)
+            .ok_or(Error::Forbidden)
+    }
+
+    /// Whether `token` is the admin token, which only opens the debug routes.
+    pub fn is_admin(&self, token: Option<&str>) -> bool {
+        match (&self.admin_token, token) {
+            (Some(admin), Some(token)) => constant_time_eq(admin.as_bytes(), token.as_bytes()),
+            _ => false,
+        }
+    }
+}
+
+impl Config {
+    /// Query string for a subscription of `user` in the `target` format that
+    /// is valid until `expires` (unix timestamp): `user=..&expires=..&sig=..`.
+    pub fn sign(&self, user: &str, target: &str, expires: u64) -> Result<String, Error> {
+        if !self.users.contains_key(user) {
+            return Err(Error::InvalidUser(
+                user.to_string(),
+                "unknown user".to_string(),
+            ));
+        }
+        let sig = self.mac(user, target, expires)?.finalize().into_bytes();
+
+        Ok(url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("user", user)
+            .append_pair("expires", &expires.to_string())
+            .append_pair("sig", &URL_SAFE_NO_PAD.encode(sig))
+            .finish())
+    }
+
+    /// Check a signature made by [`Config::sign`], rotating the signing key
+    /// revokes every url signed with the previous one.
+    pub fn verify(
+        &self,
+        user: &str,
+        target: &str,
+        expires: u64,
+        sig: &str,
+    ) -> Result<&User, Error> {
+        let user = self.users.get_key_value(user).ok_or(Error::Forbidden)?;
+        let sig = decode_base64(sig).map_err(|_| Error::Forbidden)?;
+
+        // no signing key means no url can be valid
+        self.mac(user.0, target, expires)
+            .map_err(|_| Error::Forbidden)?
+            .verify_slice(&sig)
+            .map_err(|_| Error::Forbidden)?;
+        if expires <= now() {
+            return Err(Error::Expired);
+        }
+
+        Ok(user.1)
+    }
+
+    fn mac(&self, user: &str, target: &str, expires: u64) -> Result<Hmac<Sha256>, Error> {
+        let key = self
+            .signing_key
+            .as_ref()
+            .ok_or(Error::MissingField("signing-key".to_string()))?;
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(key.as_bytes()).map_err(|_| Error::Forbidden)?;
+        mac.update(format!("{}\n{}\n{}", user, target, expires).as_bytes());
+
+        Ok(mac)
+    }
+}
+
+// do not leak how much of a token matched through timing
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Extracts the user from a `?token=` query, a `:token` path segment or a
+/// signed url, rejecting with 401 when there is none and 403 when it is
+/// unknown, badly signed or expired. The target a url is signed for is the
+/// last segment of its path, so a url signed for `sub` is good for every
+/// format `sub` negotiates.
+#[derive(Debug, Clone)]
+pub struct Access(pub Option<User>);
+
+impl Access {
+    pub fn allows(&self, group: &str) -> bool {
+        self.0.as_ref().is_none_or(|user| user.allows(group))
+    }
+
+    pub fn filter(&self, proxies: HashMap<String, Vec<Proxy>>) -> HashMap<String, Vec<Proxy>> {
+        match &self.0 {
+            Some(user) => user.filter(proxies),
+            None => proxies,
+        }
+    }
+}
+
+/// Whether the request carries the admin token, read like the token of
+/// [`Access`]. Never rejects.
+#[derive(Debug, Clone, Copy)]
+pub struct Admin(pub bool);
+
+// the query and the token from `?token=` or the `:token` path segment
+async fn credentials(
+    parts: &mut Parts,
+    state: &AppState,
+) -> (HashMap<String, String>, Option<String>) {
+    let query = Query::<HashMap<String, String>>::from_request_parts(parts, state)
+        .await
+        .map(|Query(query)| query)
+        .unwrap_or_default();
+    let path = Path::<HashMap<String, String>>::from_request_parts(parts, state)
+        .await
+        .map(|Path(path)| path)
+        .unwrap_or_default();
+    let token = query.get("token").or(path.get("token")).cloned();
+
+    (query, token)
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for Admin {
+    type Rejection = Error;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let (_, token) = credentials(parts, state).await;
+
+        Ok(Admin(state.snapshot().config.is_admin(token.as_deref())))
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for Access {
+    type Rejection = Error;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let (query, token) = credentials(parts, state).await;
+        let target = parts.uri.path().rsplit('/').next().unwrap_or_default();
+
+        let snapshot = state.snapshot();
+        let user = match (query.get("user"), query.get("expires"), query.get("sig")) {
+            (Some(user), Some(expires), Some(sig)) => {
+                let expires = expires.parse().map_err(|_| Error::Forbidden)?;
+                snapshot.config.verify(user, target, expires, sig).map(Some)
+            }
+            _ => snapshot.config.authorize(token.as_deref()),
+        };
+
+        Ok(Access(user?.cloned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::upstream::Cache;
+    use axum::{http::StatusCode, routing::get, Router};
+
+    fn config() -> Config {
+        toml::from_str(
+            r#"
+            signing-key = "key"
+            [users.alice]
+            token = "alice-secret"
+            [users.bob]
+            token = "bob-secret"
+            groups = ["Proxy"]
+            [groups]
+            Proxy = ["HK", "DIRECT"]
+            HK = ["trojan://password@hk.example.com:443#HK 01"]
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_authorize() {
+        let cfg = config();
+        assert!(cfg.validate().is_ok());
+
+        assert!(matches!(cfg.authorize(None), Err(Error::Unauthorized)));
+        assert!(matches!(
+            cfg.authorize(Some("alice")),
+            Err(Error::Forbidden)
+        ));
+        assert_eq!(
+            cfg.authorize(Some("bob-secret")).unwrap(),
+            Some(&cfg.users["bob"])
+        );
+
+        let open: Config = toml::from_str("[groups]\nProxy = []\n").unwrap();
+        assert_eq!(open.authorize(None).unwrap(), None);
+    }
+
+    #[test]
+    fn test_sign() {
+        let mut cfg = config();
+        let expires = now() + 60;
+        let query = cfg.sign("bob", "clash", expires).unwrap();
+        let pairs: HashMap<String, String> = url::form_urlencoded::parse(query.as_bytes())
+            .into_owned()
+            .collect();
+        let sig = &pairs["sig"];
+
+        assert_eq!(pairs["expires"], expires.to_string());
+        assert_eq!(
+            cfg.verify("bob", "clash", expires, sig).unwrap(),
+            &cfg.users["bob"]
+        );
+        // bound to the user, the target and the expiry
+        for (user, target, expires) in [
+            ("alice", "clash", expires),
+            ("bob", "sing-box", expires),
+            ("bob", "clash", expires + 1),
+        ] {
+            assert!(matches!(
+                cfg.verify(user, target, expires, sig),
+                Err(Error::Forbidden)
+            ));
+        }
+
+        let expired = cfg.sign("bob", "clash", now() - 1).unwrap();
+        let expired: HashMap<String, String> = url::form_urlencoded::parse(expired.as_bytes())
+            .into_owned()
+            .collect();
+        assert!(matches!(
+            cfg.verify("bob", "clash", now() - 1, &expired["sig"]),
+            Err(Error::Expired)
+        ));
+        assert!(cfg.sign("carol", "clash", expires).is_err());
+
+        cfg.signing_key = Some("rotated".to_string());
+        assert!(matches!(
+            cfg.verify("bob", "clash", expires, sig),
+            Err(Error::Forbidden)
+        ));
+    }
+
+    #[test]
+    fn test_filter() {
+        let cfg = config();
+        let proxies: HashMap<String, Vec<Proxy>> = cfg
+            .groups
+            .iter()
+            .map(|(name, group)| {
+                let members = group
+                    .entries()
+                    .iter()
+                    .map(|e| Proxy::try_from(e).unwrap())
+                    .collect();
+                (name.clone(), members)
+            })
+            .collect();
+
+        assert_eq!(cfg.users["alice"].filter(proxies.clone()), proxies);
+
+        let filtered = cfg.users["bob"].filter(proxies);
+        assert_eq!(filtered.len(), 1);
+        assert_eq!(filtered["Proxy"], [Proxy::Direct]);
+    }
+
+    #[tokio::test]
+    async fn test_access() {
+        let dir = std::env::temp_dir().join(format!("sub-provider-auth-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("config.toml"),
+            "signing-key = \"key\"\nadmin-token = \"admin-secret\"\n[users.bob]\ntoken = \"bob-secret\"\n[groups]\nProxy = []\n",
+        )
+        .unwrap();
+        let state = AppState::load(dir.join("config.toml"), Cache::default())
+            .await
+            .unwrap();
+        let signed = state
+            .snapshot()
+            .config
+            .sign("bob", "sub", now() + 60)
+            .unwrap();
+        let expired = state
+            .snapshot()
+            .config
+            .sign("bob", "sub", now() - 1)
+            .unwrap();
+        let other = state
+            .snapshot()
+            .config
+            .sign("bob", "other", now() + 60)
+            .unwrap();
+
+        let routes = Router::new()
+            .route("/sub", get(|_: Access| async { "ok" }))
+            .route(
+                "/admin",
+                get(|Admin(admin): Admin| async move { admin.to_string() }),
+            );
+        let app = Router::new()
+            .merge(routes.clone())
+            .nest("/:token", routes)
+            .with_state(state);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        for (path, status) in [
+            ("/sub", StatusCode::UNAUTHORIZED),
+            ("/sub?token=alice-secret", StatusCode::FORBIDDEN),
+            ("/alice-secret/sub", StatusCode::FORBIDDEN),
+            ("/sub?token=bob-secret", StatusCode::OK),
+            ("/bob-secret/sub", StatusCode::OK),
+            (&format!("/sub?{}", signed), StatusCode::OK),
+            (&format!("/sub?{}", expired), StatusCode::FORBIDDEN),
+            (&format!("/sub?{}", other), StatusCode::FORBIDDEN),
+        ] {
+            let response = reqwest::get(format!("http://{}{}", addr, path))
+                .await
+                .unwrap();
+            assert_eq!(response.status().as_u16(), status.as_u16(), "{}", path);
+        }
+
+        for (path, admin) in [
+            ("/admin", "false"),
+            ("/admin?token=bob-secret", "false"),
+            ("/admin?token=admin-secret", "true"),
+            ("/admin-secret/admin", "true"),
+        ] {
+            let response = reqwest::get(format!("http://{}{}", addr, path))
+                .await
+                .unwrap();
+            assert_eq!(response.text().await.unwrap(), admin, "{}", path);
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,18 +1,677 @@
+use crate::auth::User;
 use crate::error::Error;
+use crate::profile::Profile;
+use crate::proxy::{common::IpVersion, Proxy, PROXY_DIRECT, PROXY_REJECT};
+use crate::rule::{Rule, RuleProvider};
+use crate::upstream::Upstream;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-#[derive(Deserialize, Serialize)]
+use serde_enum_str::{Deserialize_enum_str, Serialize_enum_str};
+use std::collections::{HashMap, HashSet};
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Config {
-    pub groups: HashMap<String, Vec<String>>,
+    /// Path to a base clash profile (dns, tun, ports...), relative to this file.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+    /// Key signed subscription urls are made with, see `sub-provider sign`.
+    #[serde(default, rename = "signing-key")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signing_key: Option<String>,
+    /// Opens `/debug/parse` on every group, also when there are no users.
+    #[serde(default, rename = "admin-token")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub admin_token: Option<String>,
+    /// Fail renders listing the entries that do not parse, instead of leaving
+    /// them out.
+    #[serde(default, skip_serializing_if = "crate::util::is_false")]
+    pub strict: bool,
+    #[serde(default)]
+    pub profile: Profile,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<Rule>,
+    #[serde(default, rename = "rule-providers")]
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub rule_providers: HashMap<String, RuleProvider>,
+    /// Who may fetch subscriptions, anyone when empty.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub users: HashMap<String, User>,
+    /// Remote subscriptions merged into the groups, by name.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub upstreams: HashMap<String, Upstream>,
+    pub groups: HashMap<String, Group>,
+}
+
+/// A proxy group, either a plain list of entries rendered as `select`, or a
+/// table with the group options next to its `proxies`.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Group {
+    Entries(Vec<Entry>),
+    Table {
+        #[serde(flatten)]
+        options: GroupOptions,
+        #[serde(default)]
+        proxies: Vec<Entry>,
+    },
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct GroupOptions {
+    #[serde(rename = "type", default)]
+    pub group_type: GroupType,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interval: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tolerance: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lazy: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strategy: Option<Strategy>,
+}
+
+#[derive(Deserialize_enum_str, Serialize_enum_str, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum GroupType {
+    #[default]
+    Select,
+    UrlTest,
+    Fallback,
+    LoadBalance,
+    Relay,
+}
+
+#[derive(Deserialize_enum_str, Serialize_enum_str, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Strategy {
+    ConsistentHashing,
+    RoundRobin,
+    StickySessions,
+}
+
+/// A group entry, either a bare share link or a table overriding parts of it.
+/// A bare string that is not a link refers to another group, DIRECT or REJECT.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Entry {
+    Url(String),
+    Table(EntryTable),
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct EntryTable {
+    pub url: String,
+    pub name: Option<String>,
+    pub udp: Option<bool>,
+    pub ip_version: Option<IpVersion>,
+    pub skip_cert_verify: Option<bool>,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Selected by `?tags=` on the subscription url.
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+// empty, or left as in the example config
+fn is_placeholder(secret: &str) -> bool {
+    let secret = secret.trim();
+    secret.is_empty() || (secret.starts_with('<') && secret.ends_with('>'))
+}
+
+fn default_enabled() -> bool {
+    true
 }
 
 impl Config {
     pub fn from_file(path: &str) -> Result<Self, Error> {
-        toml::from_str(
-            std::fs::read_to_string(path)
-                .map_err(|e| Error::Io(e))?
-                .as_str(),
+        toml::from_str(std::fs::read_to_string(path).map_err(Error::Io)?.as_str())
+            .map_err(Error::Toml)
+    }
+}
+
+impl Config {
+    /// Check that every group reference points to an existing group and that
+    /// groups do not contain themselves, directly or through other groups.
+    /// An empty signing key, admin token or user token, or one left as the
+    /// `<...>` placeholder, is refused.
+    pub fn validate(&self) -> Result<(), Error> {
+        for (field, secret) in [
+            ("signing-key", &self.signing_key),
+            ("admin-token", &self.admin_token),
+        ] {
+            match secret.as_deref() {
+                Some(secret) if is_placeholder(secret) => {
+                    return Err(Error::InvalidValue(field.to_string(), secret.to_string()));
+                }
+                _ => {}
+            }
+        }
+
+        for (name, group) in &self.groups {
+            for reference in group.entries().iter().filter_map(Entry::reference) {
+                if reference != PROXY_DIRECT
+                    && reference != PROXY_REJECT
+                    && !self.groups.contains_key(reference)
+                {
+                    return Err(Error::DanglingReference(
+                        name.clone(),
+                        reference.to_string(),
+                    ));
+                }
+            }
+        }
+
+        for (name, upstream) in &self.upstreams {
+            upstream.validate(name)?;
+            if let Some(group) = upstream
+                .groups
+                .iter()
+                .find(|g| !self.groups.contains_key(*g))
+            {
+                return Err(Error::InvalidUpstream(
+                    name.clone(),
+                    format!("unknown group '{}'", group),
+                ));
+            }
+        }
+
+        let mut tokens = HashSet::new();
+        let mut users: Vec<(&String, &User)> = self.users.iter().collect();
+        users.sort_by_key(|(name, _)| *name);
+        for (name, user) in users {
+            if is_placeholder(&user.token) {
+                return Err(Error::InvalidValue(
+                    format!("users.{}.token", name),
+                    user.token.clone(),
+                ));
+            }
+            let unknown = user
+                .groups
+                .iter()
+                .flatten()
+                .find(|g| !self.groups.contains_key(*g));
+            if let Some(group) = unknown {
+                return Err(Error::InvalidUser(
+                    name.clone(),
+                    format!("unknown group '{}'", group),
+                ));
+            }
+            if !tokens.insert(user.token.as_str())
+                || self.admin_token.as_deref() == Some(user.token.as_str())
+            {
+                return Err(Error::InvalidUser(
+                    name.clone(),
+                    "token already used".to_string(),
+                ));
+            }
+        }
+
+        let mut done = HashSet::new();
+        let mut names: Vec<&String> = self.groups.keys().collect();
+        names.sort();
+        for name in &names {
+            self.check_cycle(name, &mut vec![], &mut done)?;
+        }
+
+        // clients key proxies by name, the same proxy may be in several groups
+        let mut taken: HashMap<String, Proxy> = HashMap::new();
+        for name in names {
+            let entries = self.groups[name].entries().iter();
+            for entry in entries.filter(|e| e.enabled() && e.reference().is_none()) {
+                let Ok(proxy) = Proxy::try_from(entry) else {
+                    continue;
+                };
+                if self.name_taken(&taken, &proxy) {
+                    return Err(Error::DuplicateName(name.clone(), proxy.name().to_string()));
+                }
+                taken.insert(proxy.name().to_string(), proxy);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether a group, DIRECT, REJECT or a different proxy in `taken` already
+    /// has the name of `proxy`.
+    pub fn name_taken(&self, taken: &HashMap<String, Proxy>, proxy: &Proxy) -> bool {
+        match taken.get(proxy.name()) {
+            Some(other) => other != proxy,
+            None => {
+                self.groups.contains_key(proxy.name())
+                    || proxy.name() == PROXY_DIRECT
+                    || proxy.name() == PROXY_REJECT
+            }
+        }
+    }
+
+    /// Check the rule providers and what the rules refer to. A target that is
+    /// neither a group nor one of the parsed proxies only gets a warning, it
+    /// may be a node of an upstream that is down, and the rule is left out
+    /// when rendering.
+    pub fn validate_rules<'a>(
+        &'a self,
+        proxy_names: impl IntoIterator<Item = &'a str>,
+    ) -> Result<(), Error> {
+        for (name, provider) in &self.rule_providers {
+            provider.validate(name)?;
+        }
+
+        let mut targets: HashSet<&str> = proxy_names.into_iter().collect();
+        targets.extend(self.groups.keys().map(String::as_str));
+        targets.extend([PROXY_DIRECT, PROXY_REJECT]);
+        let providers: HashSet<&str> = self.rule_providers.keys().map(String::as_str).collect();
+
+        for rule in &self.rules {
+            if targets.insert(rule.target.as_str()) {
+                eprintln!(
+                    "rule '{}' refers to unknown '{}', it is left out for now",
+                    rule, rule.target
+                );
+            }
+            rule.validate(&targets, &providers)?;
+        }
+
+        Ok(())
+    }
+
+    fn check_cycle<'a>(
+        &'a self,
+        name: &'a str,
+        path: &mut Vec<&'a str>,
+        done: &mut HashSet<&'a str>,
+    ) -> Result<(), Error> {
+        if done.contains(name) {
+            return Ok(());
+        }
+        if let Some(start) = path.iter().position(|n| *n == name) {
+            let mut cycle: Vec<String> = path[start..].iter().map(|n| n.to_string()).collect();
+            cycle.push(name.to_string());
+            return Err(Error::GroupCycle(cycle.join(" -> ")));
+        }
+
+        path.push(name);
+        if let Some(group) = self.groups.get(name) {
+            for reference in group.entries().iter().filter_map(Entry::reference) {
+                self.check_cycle(reference, path, done)?;
+            }
+        }
+        path.pop();
+        done.insert(name);
+
+        Ok(())
+    }
+
+    pub fn group_options(&self) -> HashMap<String, GroupOptions> {
+        self.groups
+            .iter()
+            .map(|(name, group)| (name.clone(), group.options()))
+            .collect()
+    }
+}
+
+impl Group {
+    pub fn entries(&self) -> &[Entry] {
+        match self {
+            Group::Entries(entries) => entries,
+            Group::Table { proxies, .. } => proxies,
+        }
+    }
+
+    pub fn options(&self) -> GroupOptions {
+        match self {
+            Group::Entries(_) => GroupOptions::default(),
+            Group::Table { options, .. } => options.clone(),
+        }
+    }
+}
+
+impl GroupType {
+    /// Whether the group health-checks its members and so needs a test url.
+    pub fn is_tested(&self) -> bool {
+        matches!(
+            self,
+            GroupType::UrlTest | GroupType::Fallback | GroupType::LoadBalance
         )
-        .map_err(|e| Error::Toml(e))
+    }
+}
+
+impl Entry {
+    /// Name of the group (or DIRECT / REJECT) this entry refers to.
+    pub fn reference(&self) -> Option<&str> {
+        match self {
+            Entry::Url(url) if !url.contains("://") => Some(url.trim()),
+            _ => None,
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        match self {
+            Entry::Url(url) => url,
+            Entry::Table(table) => &table.url,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        match self {
+            Entry::Url(_) => true,
+            Entry::Table(table) => table.enabled,
+        }
+    }
+}
+
+impl TryFrom<&Entry> for Proxy {
+    type Error = Error;
+
+    // values from the table win over whatever the url said
+    fn try_from(entry: &Entry) -> Result<Self, Self::Error> {
+        match entry.reference() {
+            Some(PROXY_DIRECT) => return Ok(Proxy::Direct),
+            Some(PROXY_REJECT) => return Ok(Proxy::Reject),
+            Some(name) => return Ok(Proxy::Group(name.to_string())),
+            None => {}
+        }
+
+        let mut proxy = Proxy::try_from(entry.url().to_string())?;
+
+        if let Entry::Table(table) = entry {
+            if let Some(base) = proxy.base_mut() {
+                if let Some(name) = &table.name {
+                    base.name = name.clone();
+                }
+                if let Some(udp) = table.udp {
+                    base.udp = udp;
+                }
+                if let Some(ip_version) = &table.ip_version {
+                    base.ip_version = Some(ip_version.clone());
+                }
+                base.tags = table.tags.clone();
+            }
+            if let Some(skip_cert_verify) = table.skip_cert_verify {
+                proxy.set_skip_cert_verify(skip_cert_verify);
+            }
+        }
+
+        Ok(proxy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_example_config() {
+        let cfg =
+            Config::from_file(concat!(env!("CARGO_MANIFEST_DIR"), "/config-example.toml")).unwrap();
+
+        assert_eq!(cfg.groups.len(), 4);
+        assert!(
+            matches!(&cfg.groups["group-a"].entries()[0], Entry::Table(t) if t.name.as_deref() == Some("proxy-a"))
+        );
+        assert_eq!(cfg.groups["auto"].options().group_type, GroupType::UrlTest);
+        assert_eq!(cfg.upstreams["airport"].groups, ["auto"]);
+        assert_eq!(cfg.profile.update_interval, Some(24));
+        assert_eq!(cfg.rules.len(), 7);
+        assert!(cfg.validate().is_ok());
+    }
+
+    #[test]
+    fn test_group_options() {
+        let cfg: Config = toml::from_str(
+            r#"
+            [groups.balance]
+            type = "load-balance"
+            url = "http://www.gstatic.com/generate_204"
+            interval = 300
+            strategy = "round-robin"
+            proxies = ["trojan://password@hostname:443#a"]
+
+            [groups.plain]
+            type = "relay"
+            "#,
+        )
+        .unwrap();
+
+        let options = cfg.groups["balance"].options();
+        assert_eq!(options.group_type, GroupType::LoadBalance);
+        assert_eq!(options.interval, Some(300));
+        assert_eq!(options.strategy, Some(Strategy::RoundRobin));
+        assert_eq!(cfg.groups["balance"].entries().len(), 1);
+
+        assert_eq!(cfg.groups["plain"].options().group_type, GroupType::Relay);
+        assert!(cfg.groups["plain"].entries().is_empty());
+    }
+
+    #[test]
+    fn test_references() {
+        let cfg: Config = toml::from_str(
+            r#"
+            [groups]
+            Proxy = ["Auto", "HK", "DIRECT"]
+            Auto = ["HK"]
+            HK = ["trojan://password@hostname:443#hk", "REJECT"]
+            "#,
+        )
+        .unwrap();
+
+        cfg.validate().unwrap();
+
+        let members: Vec<Proxy> = cfg.groups["Proxy"]
+            .entries()
+            .iter()
+            .map(|e| Proxy::try_from(e).unwrap())
+            .collect();
+        assert_eq!(
+            members,
+            vec![
+                Proxy::Group("Auto".to_string()),
+                Proxy::Group("HK".to_string()),
+                Proxy::Direct
+            ]
+        );
+    }
+
+    #[test]
+    fn test_duplicate_names() {
+        let validate = |groups: &str| {
+            toml::from_str::<Config>(&format!("[groups]\n{}", groups))
+                .unwrap()
+                .validate()
+        };
+
+        assert!(validate(
+            r#"
+            A = ["trojan://password@a.example.com:443#HK"]
+            B = ["trojan://password@a.example.com:443#HK", "A"]
+            "#
+        )
+        .is_ok());
+        assert!(matches!(
+            validate(
+                r#"
+                A = ["trojan://password@a.example.com:443#HK"]
+                B = ["trojan://password@b.example.com:443#HK"]
+                "#
+            ),
+            Err(Error::DuplicateName(group, name)) if group == "B" && name == "HK"
+        ));
+        assert!(matches!(
+            validate(r#"A = ["trojan://password@a.example.com:443#DIRECT"]"#),
+            Err(Error::DuplicateName(group, name)) if group == "A" && name == "DIRECT"
+        ));
+        assert!(matches!(
+            validate(
+                r#"
+                A = ["trojan://password@a.example.com:443#B"]
+                B = ["DIRECT"]
+                "#
+            ),
+            Err(Error::DuplicateName(group, name)) if group == "A" && name == "B"
+        ));
+    }
+
+    #[test]
+    fn test_invalid_references() {
+        let cfg: Config = toml::from_str(
+            r#"
+            [groups]
+            Proxy = ["Auto", "US"]
+            Auto = ["DIRECT"]
+            "#,
+        )
+        .unwrap();
+        assert!(matches!(
+            cfg.validate(),
+            Err(Error::DanglingReference(group, name)) if group == "Proxy" && name == "US"
+        ));
+
+        let cfg: Config = toml::from_str(
+            r#"
+            [groups]
+            A = ["B"]
+            B = ["C"]
+            C = ["A"]
+            "#,
+        )
+        .unwrap();
+        assert!(matches!(
+            cfg.validate(),
+            Err(Error::GroupCycle(cycle)) if cycle == "A -> B -> C -> A"
+        ));
+
+        let cfg: Config = toml::from_str(
+            r#"
+            [upstreams.airport]
+            url = "https://example.com/sub"
+            groups = ["US"]
+            [groups]
+            HK = []
+            "#,
+        )
+        .unwrap();
+        assert!(matches!(
+            cfg.validate(),
+            Err(Error::InvalidUpstream(name, _)) if name == "airport"
+        ));
+
+        let cfg: Config = toml::from_str(
+            r#"
+            [users.alice]
+            token = "secret"
+            [users.bob]
+            token = "secret"
+            [groups]
+            HK = []
+            "#,
+        )
+        .unwrap();
+        assert!(matches!(
+            cfg.validate(),
+            Err(Error::InvalidUser(name, _)) if name == "bob"
+        ));
+
+        for field in ["signing-key", "admin-token"] {
+            for secret in ["", "  ", "<random-secret>"] {
+                let cfg: Config =
+                    toml::from_str(&format!("{} = \"{}\"\n[groups]\nHK = []", field, secret))
+                        .unwrap();
+                assert!(matches!(
+                    cfg.validate(),
+                    Err(Error::InvalidValue(f, _)) if f == field
+                ));
+            }
+        }
+        for token in ["", "<secret>"] {
+            let cfg: Config = toml::from_str(&format!(
+                "[users.alice]\ntoken = \"{}\"\n[groups]\nHK = []",
+                token
+            ))
+            .unwrap();
+            assert!(matches!(
+                cfg.validate(),
+                Err(Error::InvalidValue(f, _)) if f == "users.alice.token"
+            ));
+        }
+
+        let cfg: Config = toml::from_str(
+            r#"
+            admin-token = "secret"
+            [users.alice]
+            token = "secret"
+            [groups]
+            HK = []
+            "#,
+        )
+        .unwrap();
+        assert!(matches!(
+            cfg.validate(),
+            Err(Error::InvalidUser(name, _)) if name == "alice"
+        ));
+    }
+
+    #[test]
+    fn test_rules() {
+        let cfg: Config = toml::from_str(
+            r#"
+            rules = [
+                "RULE-SET,ads,REJECT",
+                "DOMAIN-SUFFIX,google.com,Proxy",
+                "PROCESS-NAME,curl,hk",
+                "MATCH,DIRECT",
+            ]
+
+            [rule-providers.ads]
+            type = "inline"
+            behavior = "domain"
+            payload = ["+.ads.example"]
+
+            [groups]
+            Proxy = ["trojan://password@hostname:443#hk"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(cfg.rules.len(), 4);
+        assert!(cfg.validate_rules(["hk"]).is_ok());
+        // e.g. an upstream node while the upstream is down
+        assert!(cfg.validate_rules([]).is_ok());
+
+        let mut cfg = cfg;
+        cfg.rule_providers.clear();
+        assert!(matches!(
+            cfg.validate_rules(["hk"]),
+            Err(Error::UnknownRuleTarget(_, t)) if t == "ads"
+        ));
+    }
+
+    #[test]
+    fn test_overrides() {
+        let cfg: Config = toml::from_str(
+            r#"
+            [groups]
+            group = [
+                "trojan://password@hostname:443#bare",
+                { url = "trojan://password@hostname:443?security=tls#from-url", name = "renamed", udp = true, ip-version = "ipv6", skip-cert-verify = true, tags = ["hk"] },
+                { url = "trojan://password@hostname:443#disabled", enabled = false },
+            ]
+            "#,
+        )
+        .unwrap();
+        let entries = cfg.groups["group"].entries();
+
+        assert_eq!(Proxy::try_from(&entries[0]).unwrap().name(), "bare");
+        assert!(!entries[2].enabled());
+
+        let proxy = Proxy::try_from(&entries[1]).unwrap();
+        let Proxy::Trojan(trojan) = proxy else {
+            panic!("expected trojan");
+        };
+        assert_eq!(trojan.base.name, "renamed");
+        assert!(trojan.base.udp);
+        assert_eq!(trojan.base.ip_version, Some(IpVersion::Ipv6));
+        assert_eq!(trojan.base.tags, ["hk"]);
+        assert!(trojan.tls.unwrap().skip_cert_verify);
     }
 }
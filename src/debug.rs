@@ -0,0 +1,105 @@
+use serde::Serialize;
+
+use crate::{
+    config::{Config, Entry},
+    proxy::Proxy,
+    util::mask_url,
+};
+
+/// What the parser made of one config entry.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct EntryReport {
+    pub group: String,
+    /// The entry with its credentials masked.
+    pub entry: String,
+    pub enabled: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Query parameters of the link that did not make it into the proxy.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub ignored: Vec<String>,
+}
+
+/// Parse every entry of the `visible` groups again and report the result, the
+/// groups sorted by name and their entries in config order.
+pub fn report(config: &Config, visible: impl Fn(&str) -> bool) -> Vec<EntryReport> {
+    let mut groups: Vec<_> = config
+        .groups
+        .iter()
+        .filter(|(name, _)| visible(name))
+        .collect();
+    groups.sort_by_key(|(name, _)| *name);
+
+    groups
+        .into_iter()
+        .flat_map(|(name, group)| group.entries().iter().map(move |e| entry(name, e)))
+        .collect()
+}
+
+fn entry(group: &str, entry: &Entry) -> EntryReport {
+    let (proxy, error, ignored) = match Proxy::try_from(entry) {
+        Ok(proxy) => {
+            let ignored = url::Url::parse(entry.url())
+                .map(|url| proxy.ignored_query(&url))
+                .unwrap_or_default();
+            (Some(proxy.masked()), None, ignored)
+        }
+        Err(e) => (None, Some(e.to_string()), vec![]),
+    };
+
+    EntryReport {
+        group: group.to_string(),
+        entry: match entry.reference() {
+            Some(reference) => reference.to_string(),
+            None => mask_url(entry.url()),
+        },
+        enabled: entry.enabled(),
+        proxy,
+        error,
+        ignored,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let cfg: Config = toml::from_str(
+            r#"
+            [groups]
+            Proxy = ["HK", "DIRECT"]
+            HK = [
+                "trojan://password@hk.example.com:443?security=tls&sni=hk.example.com&allowInsecure=1#HK",
+                "trojan://password@hk.example.com:443?udp=yes#broken",
+                { url = "vless://uuid@hk.example.com:443#off", enabled = false },
+            ]
+            "#,
+        )
+        .unwrap();
+
+        let report = report(&cfg, |_| true);
+        assert_eq!(report.len(), 5);
+        assert_eq!(report[0].group, "HK");
+
+        assert_eq!(report[0].entry, "trojan://***@hk.example.com:443#HK");
+        let proxy = report[0].proxy.as_ref().unwrap();
+        assert_eq!(proxy["password"], "***");
+        assert_eq!(proxy["server"], "hk.example.com");
+        assert!(report[0].ignored.is_empty());
+
+        assert!(report[1].proxy.is_none());
+        assert_eq!(report[1].error.as_deref(), Some("Invalid url 'udp=yes'"));
+
+        assert!(!report[2].enabled);
+        assert_eq!(report[2].proxy.as_ref().unwrap()["uuid"], "***");
+
+        assert_eq!(report[3].entry, "HK");
+        assert_eq!(report[3].proxy.as_ref().unwrap()["reference"], "HK");
+
+        assert_eq!(super::report(&cfg, |g| g == "Proxy").len(), 2);
+    }
+}
@@ -1,3 +1,9 @@
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -5,12 +11,45 @@ pub enum Error {
     #[error("Toml error '{0}'")]
     Toml(#[from] toml::de::Error),
 
+    #[error("Yaml error '{0}'")]
+    Yaml(#[from] serde_yaml::Error),
+
     #[error("IO error '{0}'")]
     Io(#[from] std::io::Error),
 
     #[error("Url parse error '{0}'")]
     UrlParse(#[from] url::ParseError),
 
+    #[error("Json error '{0}'")]
+    Json(#[from] serde_json::Error),
+
+    #[error("Base64 decode error '{0}'")]
+    Base64(#[from] base64::DecodeError),
+
+    #[error("Http error '{0}'")]
+    Http(#[from] reqwest::Error),
+
+    #[error("Regex error '{0}'")]
+    Regex(#[from] regex::Error),
+
+    #[error("Utf8 error '{0}'")]
+    Utf8(#[from] std::str::Utf8Error),
+
+    #[error("Invalid query '{0}'")]
+    Query(#[from] axum::extract::rejection::QueryRejection),
+
+    #[error("Missing field '{0}'")]
+    MissingField(String),
+
+    #[error("Invalid value '{1}' for '{0}'")]
+    InvalidValue(String, String),
+
+    #[error("Invalid port '{0}'")]
+    InvalidPort(String),
+
+    #[error("Invalid url '{0}'")]
+    InvalidUrl(String),
+
     #[error("Invalid network '{0}'")]
     InvalidNetwork(String),
 
@@ -23,6 +62,110 @@ pub enum Error {
     #[error("Invalid congestion controller '{0}'")]
     CongestionController(String),
 
+    #[error("Unsupported cipher '{0}'")]
+    UnsupportedCipher(String),
+
+    #[error("Invalid plugin '{0}'")]
+    InvalidPlugin(String),
+
+    #[error("Group '{0}' refers to unknown group '{1}'")]
+    DanglingReference(String, String),
+
+    #[error("Group cycle '{0}'")]
+    GroupCycle(String),
+
+    #[error("Group '{0}' has a proxy named '{1}', the name is taken")]
+    DuplicateName(String, String),
+
+    #[error("Invalid rule '{0}'")]
+    InvalidRule(String),
+
+    #[error("Rule '{0}' refers to unknown '{1}'")]
+    UnknownRuleTarget(String, String),
+
+    #[error("Invalid rule provider '{0}'")]
+    InvalidRuleProvider(String),
+
+    #[error("Invalid upstream '{0}': {1}")]
+    InvalidUpstream(String, String),
+
+    #[error("Invalid subscription userinfo '{0}'")]
+    InvalidUserinfo(String),
+
+    #[error("Invalid user '{0}': {1}")]
+    InvalidUser(String, String),
+
+    #[error("Missing token")]
+    Unauthorized,
+
+    #[error("Invalid token")]
+    Forbidden,
+
+    #[error("Link expired")]
+    Expired,
+
+    #[error("{} entries failed to parse", .0.len())]
+    InvalidEntries(Vec<EntryError>),
+
     #[error("Proxy type not supported '{0}'")]
     ProxyTypeNotSupported(String),
 }
+
+/// A config entry that did not parse, `entry` has its credentials masked.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct EntryError {
+    pub group: String,
+    pub entry: String,
+    pub error: String,
+}
+
+impl Error {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Error::Unauthorized => StatusCode::UNAUTHORIZED,
+            Error::Forbidden | Error::Expired => StatusCode::FORBIDDEN,
+            Error::InvalidEntries(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::Regex(_) | Error::Query(_) => StatusCode::BAD_REQUEST,
+            Error::Http(_) => StatusCode::BAD_GATEWAY,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+// `{"error": "..."}`, with the failed `entries` in strict mode
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        let mut body = serde_json::json!({ "error": self.to_string() });
+        if let Error::InvalidEntries(entries) = &self {
+            body["entries"] = serde_json::json!(entries);
+        }
+
+        (self.status(), Json(body)).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_into_response() {
+        let response = Error::Unauthorized.into_response();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = Error::InvalidEntries(vec![EntryError {
+            group: "HK".to_string(),
+            entry: "trojan://***@hk.example.com:443#HK".to_string(),
+            error: "Invalid url 'udp=yes'".to_string(),
+        }])
+        .into_response();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "1 entries failed to parse");
+        assert_eq!(body["entries"][0]["group"], "HK");
+    }
+}
@@ -0,0 +1,292 @@
+use std::collections::{HashMap, HashSet};
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Query},
+    http::request::Parts,
+};
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::{error::Error, proxy::Proxy};
+
+/// Selects what a request renders, from its query:
+/// `?groups=a,b&include=HK&exclude=expire&type=vless,trojan&tags=hk&udp=true`.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Filter {
+    /// Comma separated groups to keep.
+    pub groups: Option<String>,
+    /// Regex a proxy name has to match.
+    pub include: Option<String>,
+    /// Regex a proxy name must not match.
+    pub exclude: Option<String>,
+    /// Comma separated proxy types to keep (`ss`, `vless`...).
+    #[serde(rename = "type")]
+    pub types: Option<String>,
+    /// Comma separated tags, a proxy needs one of them.
+    pub tags: Option<String>,
+    /// Force udp on (or off) for every proxy.
+    pub udp: Option<bool>,
+}
+
+impl Filter {
+    /// Apply the filter to the proxies of each group, group references and
+    /// DIRECT / REJECT are only dropped along with the groups they point to.
+    /// Groups the filter leaves empty are dropped as well.
+    pub fn apply(
+        &self,
+        proxies: HashMap<String, Vec<Proxy>>,
+    ) -> Result<HashMap<String, Vec<Proxy>>, Error> {
+        let include = self.include.as_deref().map(Regex::new).transpose()?;
+        let exclude = self.exclude.as_deref().map(Regex::new).transpose()?;
+        let types = self.types.as_deref().map(split);
+        let tags = self.tags.as_deref().map(split);
+
+        let keep = |proxy: &Proxy| {
+            proxy.is_reference()
+                || (include.as_ref().is_none_or(|r| r.is_match(proxy.name()))
+                    && !exclude.as_ref().is_some_and(|r| r.is_match(proxy.name()))
+                    && types
+                        .as_ref()
+                        .is_none_or(|t| t.contains(proxy.proxy_type()))
+                    && tags.as_ref().is_none_or(|t| {
+                        proxy
+                            .base()
+                            .is_some_and(|b| b.tags.iter().any(|tag| t.contains(tag.as_str())))
+                    }))
+        };
+
+        let proxies = retain_groups(proxies, |group| self.selects(group));
+
+        let proxies = proxies
+            .into_iter()
+            .map(|(group, members)| {
+                let members = members
+                    .into_iter()
+                    .filter(keep)
+                    .map(|mut proxy| {
+                        if let (Some(udp), Some(base)) = (self.udp, proxy.base_mut()) {
+                            base.udp = udp;
+                        }
+                        proxy
+                    })
+                    .collect();
+                (group, members)
+            })
+            .collect();
+
+        Ok(prune_empty(proxies))
+    }
+
+    /// Whether `?groups=` asks for `group`, or for every group.
+    pub fn selects(&self, group: &str) -> bool {
+        self.groups
+            .as_deref()
+            .is_none_or(|groups| split(groups).contains(group))
+    }
+}
+
+// a query that does not deserialize is answered like every other error
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Filter {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Query(filter) = Query::<Filter>::from_request_parts(parts, state).await?;
+
+        Ok(filter)
+    }
+}
+
+/// Keep the groups `keep` accepts, references to the other groups are dropped
+/// from their members so the rendered config stays consistent.
+pub fn retain_groups(
+    proxies: HashMap<String, Vec<Proxy>>,
+    keep: impl Fn(&str) -> bool,
+) -> HashMap<String, Vec<Proxy>> {
+    let kept: HashSet<String> = proxies.keys().filter(|g| keep(g)).cloned().collect();
+
+    proxies
+        .into_iter()
+        .filter(|(group, _)| kept.contains(group))
+        .map(|(group, members)| {
+            let members = members
+                .into_iter()
+                .filter(|p| !matches!(p, Proxy::Group(name) if !kept.contains(name)))
+                .collect();
+            (group, members)
+        })
+        .collect()
+}
+
+/// Drop the groups left without members and the references to them, which may
+/// empty more groups in turn. Clash refuses a group without proxies.
+pub fn prune_empty(mut proxies: HashMap<String, Vec<Proxy>>) -> HashMap<String, Vec<Proxy>> {
+    loop {
+        let empty: HashSet<String> = proxies
+            .iter()
+            .filter(|(_, members)| members.is_empty())
+            .map(|(group, _)| group.clone())
+            .collect();
+        if empty.is_empty() {
+            return proxies;
+        }
+
+        proxies = retain_groups(proxies, |group| !empty.contains(group));
+    }
+}
+
+fn split(list: &str) -> HashSet<&str> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::Query;
+
+    fn proxies() -> HashMap<String, Vec<Proxy>> {
+        let parse = |url: &str| Proxy::try_from(url.to_string()).unwrap();
+
+        HashMap::from([
+            (
+                "Proxy".to_string(),
+                vec![
+                    Proxy::Group("HK".to_string()),
+                    Proxy::Group("US".to_string()),
+                    Proxy::Direct,
+                ],
+            ),
+            (
+                "HK".to_string(),
+                vec![
+                    parse("trojan://password@hk.example.com:443#HK%2001"),
+                    parse("vless://uuid@hk.example.com:443#HK%2002"),
+                    parse("trojan://password@hk.example.com:443#HK%20expired"),
+                ],
+            ),
+            (
+                "US".to_string(),
+                vec![parse("hysteria2://password@us.example.com:443#US%2001")],
+            ),
+        ])
+    }
+
+    fn names(proxies: &[Proxy]) -> Vec<&str> {
+        proxies.iter().map(Proxy::name).collect()
+    }
+
+    #[test]
+    fn test_default() {
+        assert_eq!(Filter::default().apply(proxies()).unwrap(), proxies());
+    }
+
+    #[test]
+    fn test_groups() {
+        let filter = Filter {
+            groups: Some("Proxy, HK".to_string()),
+            ..Default::default()
+        };
+        let proxies = filter.apply(proxies()).unwrap();
+
+        assert_eq!(proxies.len(), 2);
+        assert_eq!(names(&proxies["Proxy"]), ["HK", "DIRECT"]);
+        assert!(filter.selects("HK"));
+        assert!(!filter.selects("US"));
+        assert!(Filter::default().selects("US"));
+    }
+
+    #[test]
+    fn test_names_and_types() {
+        let filter = Filter {
+            include: Some("HK|US".to_string()),
+            exclude: Some("(?i)expired".to_string()),
+            types: Some("trojan,hysteria2".to_string()),
+            ..Default::default()
+        };
+        let proxies = filter.apply(proxies()).unwrap();
+
+        assert_eq!(names(&proxies["HK"]), ["HK 01"]);
+        assert_eq!(names(&proxies["US"]), ["US 01"]);
+        assert_eq!(proxies["Proxy"].len(), 3);
+
+        let filter = Filter {
+            include: Some("(".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(filter.apply(HashMap::new()), Err(Error::Regex(_))));
+    }
+
+    #[test]
+    fn test_tags() {
+        let mut proxies = proxies();
+        for proxy in proxies.get_mut("HK").unwrap().iter_mut().take(2) {
+            proxy.base_mut().unwrap().tags = vec!["fast".to_string()];
+        }
+
+        let filter = Filter {
+            tags: Some("fast,cheap".to_string()),
+            ..Default::default()
+        };
+        let proxies = filter.apply(proxies).unwrap();
+
+        assert_eq!(names(&proxies["HK"]), ["HK 01", "HK 02"]);
+        assert!(!proxies.contains_key("US"));
+    }
+
+    #[test]
+    fn test_prunes_emptied_groups() {
+        let filter = Filter {
+            include: Some("HK".to_string()),
+            ..Default::default()
+        };
+        let filtered = filter.apply(proxies()).unwrap();
+
+        assert!(!filtered.contains_key("US"));
+        assert_eq!(names(&filtered["Proxy"]), ["HK", "DIRECT"]);
+
+        // emptying every group a group refers to empties that one too
+        let filter = Filter {
+            include: Some("JP".to_string()),
+            ..Default::default()
+        };
+        let proxies = filter.apply(proxies()).unwrap();
+
+        assert_eq!(proxies.keys().collect::<Vec<_>>(), ["Proxy"]);
+        assert_eq!(names(&proxies["Proxy"]), ["DIRECT"]);
+    }
+
+    #[tokio::test]
+    async fn test_rejection() {
+        use axum::{routing::get, Router};
+
+        let app = Router::new().route("/", get(|_: Filter| async { "ok" }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let response = reqwest::get(format!("http://{}/?udp=yes", addr))
+            .await
+            .unwrap();
+        assert_eq!(response.status().as_u16(), 400);
+        let body: serde_json::Value =
+            serde_json::from_str(&response.text().await.unwrap()).unwrap();
+        assert!(body["error"].as_str().unwrap().starts_with("Invalid query"));
+    }
+
+    #[test]
+    fn test_udp() {
+        let uri = "/clash?udp=true&token=secret".parse().unwrap();
+        let Query(filter) = Query::<Filter>::try_from_uri(&uri).unwrap();
+        let mut proxies = filter.apply(proxies()).unwrap();
+
+        assert!(proxies
+            .values_mut()
+            .flatten()
+            .filter_map(Proxy::base_mut)
+            .all(|base| base.udp));
+    }
+}
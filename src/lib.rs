@@ -1,5 +1,12 @@
+pub mod auth;
 pub mod config;
+pub mod debug;
 pub mod error;
+pub mod filter;
+pub mod profile;
 pub mod provider;
 pub mod proxy;
+pub mod rule;
+pub mod state;
+pub mod upstream;
 pub mod util;
@@ -1,21 +1,96 @@
 use axum::{
-    response::{Html, IntoResponse},
+    extract::{rejection::QueryRejection, Query, State},
+    http::{header::USER_AGENT, HeaderMap},
+    response::Html,
     routing::get,
-    Router,
+    Json, Router,
 };
-use std::net::SocketAddr;
+use serde::Deserialize;
+use std::{net::SocketAddr, time::Duration};
 use sub_provider::{
+    auth::{Access, Admin},
     config::Config,
-    provider::{clash::Clash, Provider},
-    proxy::Proxy,
+    debug::{self, EntryReport},
+    error::Error,
+    filter::Filter,
+    provider::{
+        clash::{Capabilities, Clash},
+        singbox::SingBox,
+        v2rayn::V2rayN,
+        Output, Provider, Target,
+    },
+    state::{AppState, Snapshot},
+    upstream::Cache,
+    util::now,
 };
 
 #[tokio::main]
 async fn main() {
-    // build our application with a route
-    let provider = Router::new()
+    let config_path = std::env::var("CONFIG_PATH").unwrap_or("config.toml".to_string());
+
+    // `sub-provider sign <user> <target> <ttl-seconds>` prints a signed url
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.as_slice() {
+        [] => {}
+        [command, user, target, ttl] if command == "sign" => {
+            // a url signed for anything but a route would never verify
+            if target != "sub" && target.parse::<Target>().is_err() {
+                let routes: Vec<String> = Target::ALL
+                    .iter()
+                    .map(Target::to_string)
+                    .chain(["sub".to_string()])
+                    .collect();
+                exit(&format!(
+                    "unknown target '{}', expected one of: {}",
+                    target,
+                    routes.join(", ")
+                ));
+            }
+            match sign(&config_path, user, target, ttl) {
+                Ok(url) => println!("{}", url),
+                Err(e) => exit(&format!("failed to sign url: {}", e)),
+            }
+            return;
+        }
+        _ => exit("usage: sub-provider [sign <user> <target> <ttl-seconds>]"),
+    }
+
+    // load the config once, then follow changes to the file
+    let cache_dir = std::env::var("CACHE_DIR").unwrap_or("cache".to_string());
+    let state = match AppState::load(&config_path, Cache::new(Some(cache_dir.into()))).await {
+        Ok(state) => state,
+        Err(e) => exit(&format!(
+            "failed to load config from {}: {}",
+            config_path, e
+        )),
+    };
+
+    let reload_interval = std::env::var("RELOAD_INTERVAL")
+        .ok()
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(5);
+    state.watch(Duration::from_secs(reload_interval));
+
+    // revalidate upstreams past their max-age in the background
+    let refresh_interval = std::env::var("REFRESH_INTERVAL")
+        .ok()
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(60);
+    state.refresh(Duration::from_secs(refresh_interval));
+
+    // build our application with a route, the token of a user can also be
+    // given as the first path segment
+    let routes = Router::new()
         .route("/clash", get(clash))
-        .route("/clash-meta", get(clash_meta));
+        .route("/clash-meta", get(clash_meta))
+        .route("/sing-box", get(sing_box))
+        .route("/v2rayn", get(v2rayn))
+        .route("/sub", get(sub))
+        .route("/debug/parse", get(debug_parse));
+    let provider = Router::new()
+        .merge(routes.clone())
+        .nest("/:token", routes)
+        .with_state(state);
 
     // read the path prefix environment variable
     let path_prefix = std::env::var("PATH_PREFIX").unwrap_or("/".to_string());
@@ -31,58 +106,172 @@ async fn main() {
 
     // run it
     let addr = SocketAddr::from(([0, 0, 0, 0], port));
-    let lisenter = tokio::net::TcpListener::bind(&addr).await.unwrap();
+    let lisenter = match tokio::net::TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(e) => exit(&format!("failed to bind {}: {}", addr, e)),
+    };
 
-    axum::serve(
+    if let Err(e) = axum::serve(
         lisenter,
         app.into_make_service_with_connect_info::<SocketAddr>(),
     )
     .await
-    .unwrap();
+    {
+        exit(&format!("server error: {}", e));
+    }
+}
+
+fn exit(message: &str) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1)
+}
+
+fn sign(config_path: &str, user: &str, target: &str, ttl: &str) -> Result<String, Error> {
+    let config = Config::from_file(config_path)?;
+    config.validate()?;
+    let ttl: u64 = ttl
+        .parse()
+        .map_err(|_| Error::InvalidUrl(format!("ttl '{}' is not a number of seconds", ttl)))?;
+    let query = config.sign(user, target, now() + ttl)?;
+
+    let path_prefix = std::env::var("PATH_PREFIX").unwrap_or("/".to_string());
+    Ok(format!(
+        "{}/{}?{}",
+        path_prefix.trim_end_matches('/'),
+        target,
+        query
+    ))
 }
 
 async fn handler() -> Html<&'static str> {
     Html("<h1>Hello, World!</h1>")
 }
 
-async fn clash() -> impl IntoResponse {
-    let config_path = std::env::var("CONFIG_PATH").unwrap_or("config.toml".to_string());
-    let cfg = Config::from_file(&config_path).unwrap();
-
-    let proxies = cfg
-        .groups
-        .into_iter()
-        .map(|(key, items)| {
-            let proxies: Vec<Proxy> = items
-                .into_iter()
-                .filter_map(|item| Proxy::try_from(item).ok())
-                .collect();
-            (key, proxies)
-        })
-        .collect();
-
-    let clash = Clash::new().with_proxies(proxies);
-
-    clash.provide()
+async fn clash(
+    State(state): State<AppState>,
+    access: Access,
+    filter: Filter,
+) -> Result<Output, Error> {
+    render(Target::Clash, &state, &access, &filter)
 }
 
-async fn clash_meta() -> impl IntoResponse {
-    let config_path = std::env::var("CONFIG_PATH").unwrap_or("config.toml".to_string());
-    let cfg = Config::from_file(&config_path).unwrap();
-
-    let proxies = cfg
-        .groups
-        .into_iter()
-        .map(|(key, items)| {
-            let proxies: Vec<Proxy> = items
-                .into_iter()
-                .filter_map(|item| Proxy::try_from(item).ok())
-                .collect();
-            (key, proxies)
-        })
-        .collect();
-
-    let clash = Clash::new().with_proxies(proxies);
-
-    clash.provide()
+async fn clash_meta(
+    State(state): State<AppState>,
+    access: Access,
+    filter: Filter,
+) -> Result<Output, Error> {
+    render(Target::ClashMeta, &state, &access, &filter)
+}
+
+async fn sing_box(
+    State(state): State<AppState>,
+    access: Access,
+    filter: Filter,
+) -> Result<Output, Error> {
+    render(Target::SingBox, &state, &access, &filter)
+}
+
+async fn v2rayn(
+    State(state): State<AppState>,
+    access: Access,
+    filter: Filter,
+) -> Result<Output, Error> {
+    render(Target::V2rayn, &state, &access, &filter)
+}
+
+#[derive(Deserialize)]
+struct Negotiate {
+    target: Option<Target>,
+}
+
+// one url for every client, the format follows `?target=` or the user agent.
+// Urls are signed for `sub`, not for the format, so a signed one serves any
+async fn sub(
+    State(state): State<AppState>,
+    access: Access,
+    headers: HeaderMap,
+    negotiate: Result<Query<Negotiate>, QueryRejection>,
+    filter: Filter,
+) -> Result<Output, Error> {
+    let Query(negotiate) = negotiate?;
+    let target = negotiate.target.unwrap_or_else(|| {
+        let user_agent = headers
+            .get(USER_AGENT)
+            .and_then(|ua| ua.to_str().ok())
+            .unwrap_or_default();
+        Target::from_user_agent(user_agent)
+    });
+
+    // caches must not hand one client's format to another
+    let output = render(target, &state, &access, &filter)?;
+    Ok(output.with_headers([("vary", "user-agent".to_string())]))
+}
+
+fn render(
+    target: Target,
+    state: &AppState,
+    access: &Access,
+    filter: &Filter,
+) -> Result<Output, Error> {
+    let snapshot = state.snapshot();
+    let proxies = filter.apply(access.filter(snapshot.proxies.clone()))?;
+    // only the groups that were asked for, also the ones left empty
+    snapshot.check(|group| access.allows(group) && filter.selects(group))?;
+    let config = &snapshot.config;
+
+    let output = match target {
+        Target::Clash | Target::ClashMeta => {
+            let clash = match target {
+                Target::Clash => Clash::new().with_capabilities(Capabilities::CLASSIC),
+                _ => Clash::new(),
+            };
+            clash
+                .with_proxies(proxies)
+                .with_group_options(&config.group_options())
+                .with_rules(&config.rules, &config.rule_providers)
+                .with_template(snapshot.template.clone())
+                .provide()
+        }
+        Target::SingBox => SingBox::new()
+            .with_proxies(proxies)
+            .with_group_options(&config.group_options())
+            .provide(),
+        Target::V2rayn => V2rayN::new()
+            .with_strict(config.strict)
+            .with_proxies(proxies)
+            .provide(),
+    };
+
+    Ok(respond(&snapshot, output?))
+}
+
+// every group for the admin token, otherwise only for known users and only
+// the groups they may use, so without users it needs the admin token
+async fn debug_parse(
+    State(state): State<AppState>,
+    Admin(admin): Admin,
+    access: Result<Access, Error>,
+) -> Result<Json<Vec<EntryReport>>, Error> {
+    let access = match (admin, access) {
+        (true, _) => Access(None),
+        (false, Ok(Access(None))) => return Err(Error::Unauthorized),
+        (false, access) => access?,
+    };
+
+    let snapshot = state.snapshot();
+    Ok(Json(debug::report(&snapshot.config, |group| {
+        access.allows(group)
+    })))
+}
+
+// every provider response carries the profile headers, and a
+// content-disposition only when a filename is configured
+fn respond(snapshot: &Snapshot, output: Output) -> Output {
+    let profile = &snapshot.config.profile;
+    let output = output.with_headers(profile.headers(snapshot.userinfo.as_ref()));
+
+    match &profile.filename {
+        Some(filename) => output.with_filename(filename.clone()),
+        None => output,
+    }
 }
@@ -0,0 +1,193 @@
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+
+pub const SUBSCRIPTION_USERINFO: &str = "subscription-userinfo";
+
+/// What clients show about the subscription itself, sent as response headers.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct Profile {
+    /// Name clients give the imported profile, sent as `content-disposition`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filename: Option<String>,
+    /// Hours between automatic updates.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub update_interval: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub web_page_url: Option<String>,
+    /// Added to the traffic reported by the upstreams.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub userinfo: Option<Userinfo>,
+}
+
+/// Traffic and expiry, written as `upload=1; download=2; total=3; expire=4`
+/// in the `subscription-userinfo` header. Byte counts and a unix timestamp.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Userinfo {
+    #[serde(default)]
+    pub upload: u64,
+    #[serde(default)]
+    pub download: u64,
+    #[serde(default)]
+    pub total: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expire: Option<u64>,
+}
+
+impl Profile {
+    /// Response headers for this profile, `userinfo` being the aggregated one.
+    /// The filename is left to [`crate::provider::Output`].
+    pub fn headers(&self, userinfo: Option<&Userinfo>) -> Vec<(&'static str, String)> {
+        let mut headers = vec![];
+        if let Some(userinfo) = userinfo {
+            headers.push((SUBSCRIPTION_USERINFO, userinfo.to_string()));
+        }
+        if let Some(interval) = self.update_interval {
+            headers.push(("profile-update-interval", interval.to_string()));
+        }
+        if let Some(url) = &self.web_page_url {
+            headers.push(("profile-web-page-url", url.clone()));
+        }
+
+        headers
+    }
+}
+
+impl Userinfo {
+    /// Traffic adds up, the subscription expires with the first one to expire.
+    pub fn merge(self, other: Userinfo) -> Userinfo {
+        let expire = match (self.expire, other.expire) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+
+        Userinfo {
+            upload: self.upload.saturating_add(other.upload),
+            download: self.download.saturating_add(other.download),
+            total: self.total.saturating_add(other.total),
+            expire,
+        }
+    }
+}
+
+impl FromStr for Userinfo {
+    type Err = Error;
+
+    // unknown keys are ignored, some providers write floats and `expire=0`
+    // for subscriptions that never expire
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut userinfo = Userinfo::default();
+
+        for pair in s.split(';').map(str::trim).filter(|p| !p.is_empty()) {
+            let (key, value) = pair
+                .split_once('=')
+                .ok_or_else(|| Error::InvalidUserinfo(s.to_string()))?;
+            let value = value
+                .trim()
+                .parse::<f64>()
+                .map_err(|_| Error::InvalidUserinfo(s.to_string()))? as u64;
+
+            match key.trim() {
+                "upload" => userinfo.upload = value,
+                "download" => userinfo.download = value,
+                "total" => userinfo.total = value,
+                "expire" => userinfo.expire = Some(value).filter(|e| *e > 0),
+                _ => {}
+            }
+        }
+
+        Ok(userinfo)
+    }
+}
+
+impl fmt::Display for Userinfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "upload={}; download={}; total={}",
+            self.upload, self.download, self.total
+        )?;
+        if let Some(expire) = self.expire {
+            write!(f, "; expire={}", expire)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_userinfo() {
+        let userinfo: Userinfo =
+            "upload=1024; download=2048; total=1.073741824E9; expire=1700000000"
+                .parse()
+                .unwrap();
+        assert_eq!(
+            userinfo,
+            Userinfo {
+                upload: 1024,
+                download: 2048,
+                total: 1073741824,
+                expire: Some(1700000000),
+            }
+        );
+        assert_eq!(
+            userinfo.to_string(),
+            "upload=1024; download=2048; total=1073741824; expire=1700000000"
+        );
+
+        let userinfo: Userinfo = "upload=0;download=0;total=10;expire=0;".parse().unwrap();
+        assert_eq!(userinfo.expire, None);
+        assert!("upload".parse::<Userinfo>().is_err());
+    }
+
+    #[test]
+    fn test_merge() {
+        let a: Userinfo = "upload=1; download=2; total=10; expire=200"
+            .parse()
+            .unwrap();
+        let b: Userinfo = "upload=3; download=4; total=20; expire=100"
+            .parse()
+            .unwrap();
+        let c: Userinfo = "upload=0; download=0; total=5".parse().unwrap();
+
+        assert_eq!(
+            a.merge(b).merge(c).to_string(),
+            "upload=4; download=6; total=35; expire=100"
+        );
+
+        // some upstreams report u64::MAX for unlimited plans
+        let unlimited: Userinfo = format!("upload=1; download=1; total={}", u64::MAX)
+            .parse()
+            .unwrap();
+        assert_eq!(unlimited.clone().merge(unlimited).total, u64::MAX);
+    }
+
+    #[test]
+    fn test_headers() {
+        let profile = Profile {
+            filename: Some("my sub".to_string()),
+            update_interval: Some(24),
+            web_page_url: Some("https://example.com".to_string()),
+            userinfo: None,
+        };
+
+        assert_eq!(
+            profile.headers(Some(&Userinfo::default())),
+            [
+                (
+                    "subscription-userinfo",
+                    "upload=0; download=0; total=0".to_string()
+                ),
+                ("profile-update-interval", "24".to_string()),
+                ("profile-web-page-url", "https://example.com".to_string()),
+            ]
+        );
+        assert!(Profile::default().headers(None).is_empty());
+    }
+}
@@ -1,5 +1,159 @@
 pub mod clash;
+pub mod singbox;
+pub mod v2rayn;
+
+use axum::{
+    http::header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+    response::{AppendHeaders, IntoResponse, Response},
+};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde_enum_str::{Deserialize_enum_str, Serialize_enum_str};
+
+use crate::error::Error;
+
+// what RFC 5987 lets through unescaped in `filename*`
+const FILENAME: &AsciiSet = &NON_ALPHANUMERIC.remove(b'.').remove(b'-').remove(b'_');
 
 pub trait Provider {
-    fn provide(&self) -> String;
+    fn provide(&self) -> Result<Output, Error>;
+}
+
+/// A rendered subscription and how to serve it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Output {
+    pub body: Vec<u8>,
+    pub content_type: &'static str,
+    /// Name clients give the imported profile, sent as `content-disposition`.
+    pub filename: Option<String>,
+    pub headers: Vec<(&'static str, String)>,
+}
+
+impl Output {
+    pub fn new(body: impl Into<Vec<u8>>, content_type: &'static str) -> Self {
+        Self {
+            body: body.into(),
+            content_type,
+            filename: None,
+            headers: vec![],
+        }
+    }
+
+    pub fn with_filename(mut self, filename: impl Into<String>) -> Self {
+        self.filename = Some(filename.into());
+        self
+    }
+
+    pub fn with_headers(
+        mut self,
+        headers: impl IntoIterator<Item = (&'static str, String)>,
+    ) -> Self {
+        self.headers.extend(headers);
+        self
+    }
+}
+
+impl IntoResponse for Output {
+    fn into_response(self) -> Response {
+        let disposition = self.filename.map(|filename| {
+            format!(
+                "attachment; filename*=UTF-8''{}",
+                utf8_percent_encode(&filename, FILENAME)
+            )
+        });
+
+        (
+            [(CONTENT_TYPE, self.content_type.to_string())],
+            AppendHeaders(disposition.map(|d| (CONTENT_DISPOSITION, d))),
+            AppendHeaders(self.headers),
+            self.body,
+        )
+            .into_response()
+    }
+}
+
+/// Output formats, named like their routes.
+#[derive(Deserialize_enum_str, Serialize_enum_str, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Target {
+    Clash,
+    ClashMeta,
+    SingBox,
+    V2rayn,
+}
+
+impl Target {
+    pub const ALL: [Target; 4] = [
+        Target::Clash,
+        Target::ClashMeta,
+        Target::SingBox,
+        Target::V2rayn,
+    ];
+
+    /// Guess the format a client understands from its `User-Agent`, clients we
+    /// do not know get base64 share links.
+    pub fn from_user_agent(user_agent: &str) -> Target {
+        let user_agent = user_agent.to_lowercase();
+        let matches = |names: &[&str]| names.iter().any(|n| user_agent.contains(n));
+
+        if matches(&["clash.meta", "clash-meta", "mihomo", "clash-verge", "stash"]) {
+            Target::ClashMeta
+        } else if matches(&["clash"]) {
+            Target::Clash
+        } else if matches(&["sing-box", "sfa", "sfi", "sfm"]) {
+            Target::SingBox
+        } else {
+            Target::V2rayn
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_user_agent() {
+        for (user_agent, target) in [
+            ("clash.meta/v1.18.0", Target::ClashMeta),
+            ("mihomo/1.18.3", Target::ClashMeta),
+            ("clash-verge/v1.6.0", Target::ClashMeta),
+            ("ClashforWindows/0.20.39", Target::Clash),
+            ("ClashX/1.118.0", Target::Clash),
+            ("sing-box 1.8.0", Target::SingBox),
+            ("SFA/1.8.0 (android)", Target::SingBox),
+            ("Shadowrocket/2070 CFNetwork/1485", Target::V2rayn),
+            ("v2rayN/6.42", Target::V2rayn),
+            ("Mozilla/5.0", Target::V2rayn),
+            ("", Target::V2rayn),
+        ] {
+            assert_eq!(
+                Target::from_user_agent(user_agent),
+                target,
+                "{}",
+                user_agent
+            );
+        }
+
+        assert_eq!("sing-box".parse::<Target>().unwrap(), Target::SingBox);
+        assert_eq!(Target::ClashMeta.to_string(), "clash-meta");
+    }
+
+    #[test]
+    fn test_into_response() {
+        let response = Output::new("body", "text/plain; charset=utf-8")
+            .with_filename("my sub.yaml")
+            .with_headers([("profile-update-interval", "24".to_string())])
+            .into_response();
+        let headers = response.headers();
+
+        assert_eq!(headers[CONTENT_TYPE], "text/plain; charset=utf-8");
+        assert_eq!(
+            headers[CONTENT_DISPOSITION],
+            "attachment; filename*=UTF-8''my%20sub.yaml"
+        );
+        assert_eq!(headers["profile-update-interval"], "24");
+
+        let response = Output::new("body", "text/plain; charset=utf-8").into_response();
+        assert!(!response.headers().contains_key(CONTENT_DISPOSITION));
+    }
 }
@@ -0,0 +1,486 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+
+use super::{
+    clash::{DEFAULT_TEST_INTERVAL, DEFAULT_TEST_URL},
+    Output,
+};
+use crate::{
+    config::GroupOptions,
+    error::Error,
+    proxy::{
+        plugin::Plugin,
+        protocol::{Network, TLS},
+        Proxy, PROXY_DIRECT, PROXY_REJECT,
+    },
+};
+
+/// sing-box configuration, only `outbounds` is generated, the rest is left to the client.
+#[derive(Serialize, Debug)]
+pub struct SingBox {
+    log: Value,
+    outbounds: Vec<Value>,
+}
+
+impl SingBox {
+    pub fn new() -> Self {
+        Self {
+            log: json!({ "level": "info" }),
+            outbounds: vec![],
+        }
+    }
+
+    pub fn with_proxies(mut self, proxies: HashMap<String, Vec<Proxy>>) -> Self {
+        let mut groups: Vec<_> = proxies.iter().collect();
+        groups.sort_by(|a, b| a.0.cmp(b.0));
+
+        // sing-box uses the first outbound as the default, so groups go first
+        self.outbounds = groups
+            .iter()
+            .map(|(name, p)| {
+                group_outbound(
+                    name,
+                    &GroupOptions::default(),
+                    p.iter().map(|p| p.name().to_string()).collect(),
+                )
+            })
+            .collect();
+
+        let mut seen = HashSet::new();
+        for proxy in groups.into_iter().flat_map(|(_, p)| p) {
+            if seen.insert(proxy.name().to_string()) {
+                self.outbounds.extend(outbounds(proxy));
+            }
+        }
+
+        self.outbounds
+            .push(json!({ "type": "direct", "tag": PROXY_DIRECT }));
+        self.outbounds
+            .push(json!({ "type": "block", "tag": PROXY_REJECT }));
+        self
+    }
+}
+
+impl SingBox {
+    /// Turn the groups built by [`SingBox::with_proxies`] into the configured type.
+    pub fn with_group_options(mut self, options: &HashMap<String, GroupOptions>) -> Self {
+        for outbound in self.outbounds.iter_mut() {
+            let Some(options) = outbound["tag"].as_str().and_then(|tag| options.get(tag)) else {
+                continue;
+            };
+            if !matches!(outbound["type"].as_str(), Some("selector" | "urltest")) {
+                continue;
+            }
+
+            let members = serde_json::from_value(outbound["outbounds"].clone()).unwrap_or_default();
+            *outbound = group_outbound(
+                outbound["tag"].as_str().unwrap_or_default(),
+                options,
+                members,
+            );
+        }
+        self
+    }
+}
+
+impl Default for SingBox {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl super::Provider for SingBox {
+    fn provide(&self) -> Result<Output, Error> {
+        let body = serde_json::to_vec_pretty(self)?;
+
+        Ok(Output::new(body, "application/json"))
+    }
+}
+
+/// Map a clash group type onto the matching sing-box group outbound, sing-box
+/// only knows `urltest` for health-checked groups and has no `relay`.
+pub fn group_outbound(name: &str, options: &GroupOptions, members: Vec<String>) -> Value {
+    if !options.group_type.is_tested() {
+        return json!({
+            "type": "selector",
+            "tag": name,
+            "outbounds": members,
+        });
+    }
+
+    let mut outbound = json!({
+        "type": "urltest",
+        "tag": name,
+        "outbounds": members,
+        "url": options.url.as_deref().unwrap_or(DEFAULT_TEST_URL),
+        "interval": format!("{}s", options.interval.unwrap_or(DEFAULT_TEST_INTERVAL)),
+    });
+    if let Some(tolerance) = options.tolerance {
+        outbound["tolerance"] = json!(tolerance);
+    }
+    outbound
+}
+
+/// A proxy usually maps to a single outbound, shadowsocks over shadow-tls needs
+/// an extra `shadowtls` outbound as its detour.
+pub fn outbounds(proxy: &Proxy) -> Vec<Value> {
+    let mut outbound = Map::new();
+    let mut extra = vec![];
+
+    let base = match proxy {
+        Proxy::Direct | Proxy::Reject | Proxy::Group(_) => return vec![],
+        Proxy::Ss(ss) => {
+            outbound.insert("type".into(), json!("shadowsocks"));
+            outbound.insert("method".into(), json!(ss.cipher));
+            outbound.insert("password".into(), json!(ss.password));
+            match &ss.plugin {
+                Some(Plugin::ShadowTls(opts)) => {
+                    let detour = format!("{}-shadowtls", ss.base.name);
+                    extra.push(json!({
+                        "type": "shadowtls",
+                        "tag": detour,
+                        "server": ss.base.server,
+                        "server_port": ss.base.port,
+                        "version": opts.version,
+                        "password": opts.password,
+                        "tls": { "enabled": true, "server_name": opts.host },
+                    }));
+                    outbound.insert("detour".into(), json!(detour));
+                }
+                Some(plugin) => {
+                    let plugin = plugin.to_string();
+                    let (name, opts) = plugin.split_once(';').unwrap_or((&plugin, ""));
+                    outbound.insert("plugin".into(), json!(name));
+                    outbound.insert("plugin_opts".into(), json!(opts));
+                }
+                None => {}
+            }
+            &ss.base
+        }
+        Proxy::Socks5(socks5) => {
+            outbound.insert("type".into(), json!("socks"));
+            outbound.insert("version".into(), json!("5"));
+            insert_some(&mut outbound, "username", &socks5.username);
+            insert_some(&mut outbound, "password", &socks5.password);
+            &socks5.base
+        }
+        Proxy::Http(http) => {
+            outbound.insert("type".into(), json!("http"));
+            insert_some(&mut outbound, "username", &http.username);
+            insert_some(&mut outbound, "password", &http.password);
+            if !http.headers.is_empty() {
+                outbound.insert("headers".into(), json!(http.headers));
+            }
+            if http.tls {
+                outbound.insert(
+                    "tls".into(),
+                    json!({
+                        "enabled": true,
+                        "server_name": http.sni,
+                        "insecure": http.skip_cert_verify,
+                    }),
+                );
+            }
+            &http.base
+        }
+        Proxy::Trojan(trojan) => {
+            outbound.insert("type".into(), json!("trojan"));
+            outbound.insert("password".into(), json!(trojan.password));
+            insert_tls(&mut outbound, trojan.tls.as_ref());
+            insert_transport(&mut outbound, trojan.opts.as_ref());
+            &trojan.base
+        }
+        Proxy::Vmess(vmess) => {
+            outbound.insert("type".into(), json!("vmess"));
+            outbound.insert("uuid".into(), json!(vmess.uuid));
+            outbound.insert("alter_id".into(), json!(vmess.alter_id));
+            outbound.insert(
+                "security".into(),
+                json!(vmess.cipher.as_deref().unwrap_or("auto")),
+            );
+            insert_tls(&mut outbound, vmess.tls.as_ref());
+            insert_transport(&mut outbound, vmess.network.as_ref());
+            &vmess.base
+        }
+        Proxy::Vless(vless) => {
+            outbound.insert("type".into(), json!("vless"));
+            outbound.insert("uuid".into(), json!(vless.uuid));
+            if !vless.flow.is_empty() {
+                outbound.insert("flow".into(), json!(vless.flow));
+            }
+            insert_tls(&mut outbound, vless.tls.as_ref());
+            insert_transport(&mut outbound, vless.network.as_ref());
+            &vless.base
+        }
+        Proxy::Hysteria2(hysteria2) => {
+            outbound.insert("type".into(), json!("hysteria2"));
+            outbound.insert("password".into(), json!(hysteria2.password));
+            if !hysteria2.ports.is_empty() {
+                // clash writes port hopping ranges as 1000-2000, sing-box as 1000:2000
+                let ports: Vec<String> = hysteria2
+                    .ports
+                    .split(',')
+                    .map(|p| match p.contains('-') {
+                        true => p.replace('-', ":"),
+                        false => format!("{}:{}", p, p),
+                    })
+                    .collect();
+                outbound.insert("server_ports".into(), json!(ports));
+            }
+            if !hysteria2.obfs.is_empty() {
+                outbound.insert(
+                    "obfs".into(),
+                    json!({ "type": hysteria2.obfs, "password": hysteria2.obfs_password }),
+                );
+            }
+            // hysteria2 is always over tls
+            outbound.insert(
+                "tls".into(),
+                tls(&hysteria2.tls.clone().unwrap_or_default()),
+            );
+            &hysteria2.base
+        }
+        Proxy::Tuic(tuic) => {
+            outbound.insert("type".into(), json!("tuic"));
+            outbound.insert("uuid".into(), json!(tuic.uuid));
+            outbound.insert("password".into(), json!(tuic.password));
+            if let Some(congestion_controller) = &tuic.congestion_controller {
+                outbound.insert(
+                    "congestion_control".into(),
+                    json!(congestion_controller.to_string()),
+                );
+            }
+            if let Some(udp_relay_mode) = &tuic.udp_relay_mode {
+                outbound.insert("udp_relay_mode".into(), json!(udp_relay_mode.to_string()));
+            }
+            if let Some(heartbeat_interval) = tuic.heartbeat_interval {
+                outbound.insert(
+                    "heartbeat".into(),
+                    json!(format!("{}ms", heartbeat_interval)),
+                );
+            }
+            outbound.insert(
+                "tls".into(),
+                tls(&TLS {
+                    tls: true,
+                    alpn: tuic.alpn.clone(),
+                    ..Default::default()
+                }),
+            );
+            &tuic.base
+        }
+    };
+
+    outbound.insert("tag".into(), json!(base.name));
+    if !outbound.contains_key("detour") {
+        outbound.insert("server".into(), json!(base.server));
+        outbound.insert("server_port".into(), json!(base.port));
+    }
+
+    extra.insert(0, Value::Object(outbound));
+    extra
+}
+
+fn insert_some(outbound: &mut Map<String, Value>, key: &str, value: &Option<String>) {
+    if let Some(value) = value {
+        outbound.insert(key.into(), json!(value));
+    }
+}
+
+fn insert_tls(outbound: &mut Map<String, Value>, value: Option<&TLS>) {
+    if let Some(value) = value.filter(|t| t.tls) {
+        outbound.insert("tls".into(), tls(value));
+    }
+}
+
+fn insert_transport(outbound: &mut Map<String, Value>, value: Option<&Network>) {
+    if let Some(value) = value {
+        outbound.insert("transport".into(), transport(value));
+    }
+}
+
+pub fn tls(value: &TLS) -> Value {
+    let mut tls = json!({ "enabled": true });
+
+    if let Some(server_name) = &value.server_name {
+        tls["server_name"] = json!(server_name);
+    }
+    if value.skip_cert_verify {
+        tls["insecure"] = json!(true);
+    }
+    if !value.alpn.is_empty() {
+        tls["alpn"] = json!(value.alpn);
+    }
+    if let Some(fingerprint) = &value.client_fingerprint {
+        tls["utls"] = json!({ "enabled": true, "fingerprint": fingerprint });
+    }
+    if let Some(reality) = &value.reality_opts {
+        tls["reality"] = json!({
+            "enabled": true,
+            "public_key": reality.public_key,
+            "short_id": reality.short_id,
+        });
+        // reality requires utls on sing-box
+        if value.client_fingerprint.is_none() {
+            tls["utls"] = json!({ "enabled": true, "fingerprint": "chrome" });
+        }
+    }
+
+    tls
+}
+
+pub fn transport(value: &Network) -> Value {
+    match value {
+        Network::Http {
+            method,
+            path,
+            headers,
+        } => {
+            let mut transport = json!({
+                "type": "http",
+                "method": method,
+                "path": path.first().cloned().unwrap_or("/".to_string()),
+            });
+            if let Some(host) = headers.get("Host") {
+                transport["host"] = json!(host);
+            }
+            let headers: HashMap<_, _> = headers.iter().filter(|(k, _)| *k != "Host").collect();
+            if !headers.is_empty() {
+                transport["headers"] = json!(headers);
+            }
+            transport
+        }
+        Network::H2 { host, path } => json!({
+            "type": "http",
+            "host": host,
+            "path": path,
+        }),
+        Network::Grpc { grpc_service_name } => json!({
+            "type": "grpc",
+            "service_name": grpc_service_name.clone().unwrap_or_default(),
+        }),
+        Network::Ws {
+            path,
+            headers,
+            max_early_data,
+        } => {
+            let mut transport = json!({
+                "type": "ws",
+                "path": path,
+            });
+            if !headers.is_empty() {
+                transport["headers"] = json!(headers);
+            }
+            if let Some(max_early_data) = max_early_data {
+                transport["max_early_data"] = json!(max_early_data);
+                transport["early_data_header_name"] = json!("Sec-WebSocket-Protocol");
+            }
+            transport
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::GroupType, provider::Provider};
+
+    #[test]
+    fn test_vless_reality_grpc() {
+        let proxy = Proxy::try_from(
+            "vless://some-uuid@hostname:443?security=reality&sni=example.com&fp=safari&pbk=key&sid=id&type=grpc&serviceName=svc#vless".to_string(),
+        )
+        .unwrap();
+
+        let outbound = &outbounds(&proxy)[0];
+
+        assert_eq!(
+            outbound,
+            &json!({
+                "type": "vless",
+                "tag": "vless",
+                "server": "hostname",
+                "server_port": 443,
+                "uuid": "some-uuid",
+                "tls": {
+                    "enabled": true,
+                    "server_name": "example.com",
+                    "utls": { "enabled": true, "fingerprint": "safari" },
+                    "reality": { "enabled": true, "public_key": "key", "short_id": "id" },
+                },
+                "transport": { "type": "grpc", "service_name": "svc" },
+            })
+        );
+    }
+
+    #[test]
+    fn test_shadow_tls_detour() {
+        let proxy = Proxy::try_from(
+            "ss://YWVzLTI1Ni1nY206cGFzc3dvcmQ@hostname:443/?plugin=shadow-tls%3Bhost%3Dexample.com%3Bpassword%3Dpass%3Bversion%3D3#ss".to_string(),
+        )
+        .unwrap();
+
+        let outbounds = outbounds(&proxy);
+
+        assert_eq!(outbounds.len(), 2);
+        assert_eq!(outbounds[0]["detour"], "ss-shadowtls");
+        assert!(outbounds[0].get("server").is_none());
+        assert_eq!(outbounds[1]["type"], "shadowtls");
+        assert_eq!(outbounds[1]["server_port"], 443);
+        assert_eq!(outbounds[1]["tls"]["server_name"], "example.com");
+    }
+
+    #[test]
+    fn test_provide() {
+        let trojan = Proxy::try_from("trojan://password@hostname:443#trojan".to_string()).unwrap();
+        let proxies = HashMap::from([
+            ("b".to_string(), vec![trojan.clone()]),
+            ("a".to_string(), vec![trojan]),
+        ]);
+
+        let output = SingBox::new().with_proxies(proxies).provide().unwrap();
+        assert_eq!(output.filename, None);
+        let value: Value = serde_json::from_slice(&output.body).unwrap();
+        let tags: Vec<&str> = value["outbounds"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|o| o["tag"].as_str().unwrap())
+            .collect();
+
+        assert_eq!(tags, vec!["a", "b", "trojan", PROXY_DIRECT, PROXY_REJECT]);
+        assert_eq!(value["outbounds"][0]["type"], "selector");
+    }
+
+    #[test]
+    fn test_group_options() {
+        let trojan = Proxy::try_from("trojan://password@hostname:443#trojan".to_string()).unwrap();
+        let proxies = HashMap::from([("auto".to_string(), vec![trojan])]);
+        let options = HashMap::from([(
+            "auto".to_string(),
+            GroupOptions {
+                group_type: GroupType::UrlTest,
+                tolerance: Some(50),
+                ..Default::default()
+            },
+        )]);
+
+        let sing_box = SingBox::new()
+            .with_proxies(proxies)
+            .with_group_options(&options);
+
+        assert_eq!(
+            sing_box.outbounds[0],
+            json!({
+                "type": "urltest",
+                "tag": "auto",
+                "outbounds": ["trojan"],
+                "url": DEFAULT_TEST_URL,
+                "interval": "300s",
+                "tolerance": 50,
+            })
+        );
+    }
+}
@@ -0,0 +1,130 @@
+use std::collections::{HashMap, HashSet};
+
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+
+use super::Output;
+use crate::{
+    error::{EntryError, Error},
+    proxy::Proxy,
+};
+
+/// Base64 encoded, newline separated share links as understood by v2rayN,
+/// v2rayNG, Shadowrocket, NekoBox and friends.
+#[derive(Debug, Default)]
+pub struct V2rayN {
+    /// Proxies with the first group they are listed in.
+    proxies: Vec<(String, Proxy)>,
+    strict: bool,
+}
+
+impl V2rayN {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fail on proxies that have no share link instead of leaving them out.
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    pub fn with_proxies(mut self, proxies: HashMap<String, Vec<Proxy>>) -> Self {
+        // groups in name order keep the links in the same order on every poll,
+        // the same proxy may be listed in several groups, a link list has no groups
+        let mut groups: Vec<_> = proxies.into_iter().collect();
+        groups.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut seen = HashSet::new();
+        self.proxies = groups
+            .into_iter()
+            .flat_map(|(group, p)| p.into_iter().map(move |p| (group.clone(), p)))
+            .filter(|(_, p)| !p.is_reference() && seen.insert(p.name().to_string()))
+            .collect();
+        self
+    }
+}
+
+impl super::Provider for V2rayN {
+    fn provide(&self) -> Result<Output, Error> {
+        let mut links: Vec<String> = vec![];
+        let mut failures = vec![];
+        for (group, proxy) in &self.proxies {
+            match proxy.clone().try_into() {
+                Ok(link) => links.push(link),
+                Err(e) => failures.push(EntryError {
+                    group: group.clone(),
+                    entry: proxy.name().to_string(),
+                    error: e.to_string(),
+                }),
+            }
+        }
+
+        if self.strict && !failures.is_empty() {
+            return Err(Error::InvalidEntries(failures));
+        }
+        for failure in failures {
+            eprintln!(
+                "left {} of group {} out of the link list: {}",
+                failure.entry, failure.group, failure.error
+            );
+        }
+
+        Ok(Output::new(
+            STANDARD.encode(links.join("\n")),
+            "text/plain; charset=utf-8",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{provider::Provider, util::decode_base64_string};
+
+    #[test]
+    fn test_provide() {
+        let trojan = Proxy::try_from("trojan://password@hostname:443#trojan".to_string()).unwrap();
+        let ss = Proxy::try_from("ss://YWVzLTI1Ni1nY206cGFzc3dvcmQ@hostname:8388#ss".to_string())
+            .unwrap();
+        let proxies = HashMap::from([
+            ("b".to_string(), vec![ss.clone(), trojan.clone()]),
+            ("a".to_string(), vec![trojan.clone()]),
+        ]);
+
+        let output = V2rayN::new().with_proxies(proxies).provide().unwrap();
+        assert_eq!(output.content_type, "text/plain; charset=utf-8");
+
+        let decoded = decode_base64_string(std::str::from_utf8(&output.body).unwrap()).unwrap();
+        let parsed: Vec<Proxy> = decoded
+            .lines()
+            .map(|l| Proxy::try_from(l.to_string()).unwrap())
+            .collect();
+
+        assert_eq!(parsed, vec![trojan, ss]);
+    }
+
+    #[test]
+    fn test_export_failures() {
+        let trojan = Proxy::try_from("trojan://password@hostname:443#trojan".to_string()).unwrap();
+        let mut broken =
+            Proxy::try_from("trojan://password@hostname:443#broken".to_string()).unwrap();
+        broken.base_mut().unwrap().server = "bad host".to_string();
+        let proxies = HashMap::from([("a".to_string(), vec![broken, trojan.clone()])]);
+
+        let output = V2rayN::new()
+            .with_proxies(proxies.clone())
+            .provide()
+            .unwrap();
+        let decoded = decode_base64_string(std::str::from_utf8(&output.body).unwrap()).unwrap();
+        assert_eq!(decoded, TryInto::<String>::try_into(trojan).unwrap());
+
+        let result = V2rayN::new()
+            .with_strict(true)
+            .with_proxies(proxies)
+            .provide();
+        assert!(matches!(
+            result,
+            Err(Error::InvalidEntries(entries)) if entries.len() == 1 && entries[0].group == "a" && entries[0].entry == "broken"
+        ));
+    }
+}
@@ -1,6 +1,6 @@
 use std::str::FromStr;
 
-use crate::util::is_false;
+use crate::util::{is_false, percent_decode};
 use crate::{error::Error, util::get_query};
 use serde::{Deserialize, Serialize};
 use serde_enum_str::{Deserialize_enum_str, Serialize_enum_str};
@@ -13,8 +13,11 @@ pub struct BaseProxy {
     pub port: usize,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub ip_version: Option<IpVersion>,
-    #[serde(skip_serializing_if = "is_false")]
+    #[serde(default, skip_serializing_if = "is_false")]
     pub udp: bool,
+    /// Free-form tags from the config entry, only used to filter requests.
+    #[serde(skip)]
+    pub tags: Vec<String>,
 }
 
 #[derive(Deserialize_enum_str, Serialize_enum_str, Clone, Debug, PartialEq, Eq)]
@@ -31,22 +34,83 @@ impl TryFrom<url::Url> for BaseProxy {
     type Error = Error;
 
     fn try_from(url: url::Url) -> Result<Self, Self::Error> {
-        let ip_version = get_query("ip_version", &url).map(|s| IpVersion::from_str(&s).unwrap());
+        let ip_version = get_query("ip_version", &url)
+            .map(|s| {
+                IpVersion::from_str(&s).map_err(|_| Error::InvalidUrl(format!("ip_version={}", s)))
+            })
+            .transpose()?;
 
         let udp = get_query("udp", &url)
-            .map(|s| s.parse().unwrap())
+            .map(|s| {
+                s.parse()
+                    .map_err(|_| Error::InvalidUrl(format!("udp={}", s)))
+            })
+            .transpose()?
             .unwrap_or(false);
 
         Ok(BaseProxy {
-            name: url.fragment().unwrap_or_default().to_string(),
-            server: url.host_str().unwrap().to_string(),
-            port: url.port().map(|p| p as usize).unwrap_or(443),
+            name: fragment_name(&url),
+            server: url
+                .host_str()
+                .ok_or_else(|| Error::MissingField("server".to_string()))?
+                .to_string(),
+            port: url
+                .port_or_known_default()
+                .map(|p| p as usize)
+                .unwrap_or(443),
             ip_version,
             udp,
+            tags: vec![],
         })
     }
 }
 
+impl BaseProxy {
+    /// Build a share link for this proxy, the `query` pairs are written before
+    /// the common `ip_version` / `udp` ones and the name goes into the fragment.
+    pub fn to_url(
+        &self,
+        scheme: &str,
+        username: &str,
+        password: Option<&str>,
+        query: Vec<(&'static str, String)>,
+    ) -> Result<url::Url, Error> {
+        let server = match self.server.contains(':') && !self.server.starts_with('[') {
+            true => format!("[{}]", self.server),
+            false => self.server.clone(),
+        };
+        let mut url = url::Url::parse(&format!("{}://{}:{}", scheme, server, self.port))?;
+
+        url.set_username(username)
+            .and_then(|_| url.set_password(password))
+            .map_err(|_| Error::InvalidUrl(url.to_string()))?;
+
+        let mut query = query;
+        if let Some(ip_version) = &self.ip_version {
+            query.push(("ip_version", ip_version.to_string()));
+        }
+        if self.udp {
+            query.push(("udp", "true".to_string()));
+        }
+        if !query.is_empty() {
+            url.query_pairs_mut().extend_pairs(query);
+        }
+
+        if !self.name.is_empty() {
+            url.set_fragment(Some(&self.name));
+        }
+
+        Ok(url)
+    }
+}
+
+/// Share links percent-encode the name, fall back to the raw fragment if it
+/// does not decode to valid utf-8.
+pub fn fragment_name(url: &url::Url) -> String {
+    let fragment = url.fragment().unwrap_or_default();
+    percent_decode(fragment).unwrap_or(fragment.to_string())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -59,9 +123,26 @@ mod tests {
             port: 443,
             ip_version: Some(IpVersion::Ipv4),
             udp: false,
+            tags: vec![],
         };
         let url = url::Url::parse("https://test.com:443?ip_version=ipv4#test").unwrap();
 
         assert_eq!(BaseProxy::try_from(url).unwrap(), p);
     }
+
+    #[test]
+    fn test_to_url() {
+        let p = BaseProxy {
+            name: "香港 01".to_string(),
+            server: "test.com".to_string(),
+            port: 8443,
+            ip_version: Some(IpVersion::Ipv6Prefer),
+            udp: true,
+            tags: vec![],
+        };
+
+        let url = p.to_url("trojan", "user", None, vec![]).unwrap();
+
+        assert_eq!(BaseProxy::try_from(url).unwrap(), p);
+    }
 }
@@ -1,18 +1,25 @@
 use super::{protocol::TLS, BaseProxy};
-use crate::{error::Error, util::get_query};
+use crate::{
+    error::Error,
+    util::{get_query, percent_decode},
+};
 use serde::{Deserialize, Serialize};
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct Hysteria2 {
     #[serde(flatten)]
     pub base: BaseProxy,
     pub password: String,
-    #[serde(skip_serializing_if = "String::is_empty")]
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub ports: String,
     // pub up: String,
     // pub down: String,
-    #[serde(skip_serializing_if = "String::is_empty")]
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub obfs: String,
-    #[serde(skip_serializing_if = "String::is_empty")]
+    #[serde(
+        default,
+        alias = "obfs-password",
+        skip_serializing_if = "String::is_empty"
+    )]
     pub obfs_password: String,
 
     #[serde(flatten)]
@@ -26,16 +33,43 @@ impl TryFrom<url::Url> for Hysteria2 {
     fn try_from(value: url::Url) -> Result<Self, Self::Error> {
         Ok(Hysteria2 {
             base: BaseProxy::try_from(value.clone())?,
-            password: value.username().to_string(),
+            password: percent_decode(value.username())?,
             ports: get_query("ports", &value).unwrap_or_default(),
             obfs: get_query("obfs", &value).unwrap_or_default(),
             obfs_password: get_query("obfs-password", &value).unwrap_or_default(),
 
-            tls: TLS::try_from(value).ok(),
+            tls: Some(TLS::from_query_implied(&value)?),
         })
     }
 }
 
+impl Hysteria2 {
+    pub fn to_url(&self) -> Result<String, Error> {
+        let mut query = vec![];
+        if !self.ports.is_empty() {
+            query.push(("ports", self.ports.clone()));
+        }
+        if !self.obfs.is_empty() {
+            query.push(("obfs", self.obfs.clone()));
+        }
+        if !self.obfs_password.is_empty() {
+            query.push(("obfs-password", self.obfs_password.clone()));
+        }
+        // hysteria2 is always tls, its links do not say so
+        query.extend(
+            self.tls
+                .iter()
+                .flat_map(|tls| tls.to_query("insecure"))
+                .filter(|(key, _)| *key != "security"),
+        );
+
+        Ok(self
+            .base
+            .to_url("hysteria2", &self.password, None, query)?
+            .to_string())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -57,4 +91,31 @@ mod tests {
         assert_eq!(hysteria2.obfs, "");
         assert_eq!(hysteria2.obfs_password, "");
     }
+
+    #[test]
+    fn test_to_url() {
+        let url = "hysteria2://password@hostname:1123?obfs=salamander&obfs-password=secret&security=tls&sni=hostname&alpn=h3#hysteria2-proxy";
+
+        let hysteria2 = Hysteria2::try_from(url::Url::parse(url).unwrap()).unwrap();
+
+        let exported = hysteria2.to_url().unwrap();
+        assert_eq!(
+            Hysteria2::try_from(url::Url::parse(&exported).unwrap()).unwrap(),
+            hysteria2
+        );
+    }
+
+    #[test]
+    fn test_insecure() {
+        let url = "hysteria2://password@hostname:1123?sni=example.com&insecure=1#hysteria2-proxy";
+
+        let hysteria2 = Hysteria2::try_from(url::Url::parse(url).unwrap()).unwrap();
+        let tls = hysteria2.tls.as_ref().unwrap();
+        assert_eq!(tls.server_name.as_deref(), Some("example.com"));
+        assert!(tls.skip_cert_verify);
+
+        let exported = hysteria2.to_url().unwrap();
+        assert!(exported.contains("insecure=1"));
+        assert!(!exported.contains("security="));
+    }
 }
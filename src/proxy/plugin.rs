@@ -0,0 +1,226 @@
+use std::{fmt, str::FromStr};
+
+use crate::error::Error;
+use crate::util::is_false;
+use serde::{Deserialize, Serialize};
+use serde_enum_str::{Deserialize_enum_str, Serialize_enum_str};
+
+/// SIP003 plugin of a shadowsocks proxy, serialized as clash `plugin` / `plugin-opts`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "plugin", content = "plugin-opts", rename_all = "kebab-case")]
+pub enum Plugin {
+    Obfs(ObfsOpts),
+    V2rayPlugin(V2rayPluginOpts),
+    ShadowTls(ShadowTlsOpts),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct ObfsOpts {
+    pub mode: ObfsMode,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+}
+
+#[derive(Deserialize_enum_str, Serialize_enum_str, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ObfsMode {
+    Http,
+    Tls,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct V2rayPluginOpts {
+    pub mode: String,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub tls: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub mux: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub skip_cert_verify: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct ShadowTlsOpts {
+    pub host: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    pub version: u8,
+}
+
+// plugin strings look like `obfs-local;obfs=http;obfs-host=example.com`,
+// options without a value (e.g. `tls`) are flags
+impl FromStr for Plugin {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(';');
+        let name = parts.next().unwrap_or_default();
+        let opts: Vec<(&str, &str)> = parts
+            .filter(|p| !p.is_empty())
+            .map(|p| p.split_once('=').unwrap_or((p, "")))
+            .collect();
+        let get = |key: &str| {
+            opts.iter()
+                .find(|(k, _)| *k == key)
+                .map(|(_, v)| v.to_string())
+        };
+        let flag = |key: &str| {
+            opts.iter()
+                .any(|(k, v)| *k == key && (v.is_empty() || *v == "true" || *v == "1"))
+        };
+
+        match name {
+            "obfs-local" | "simple-obfs" | "obfs" => Ok(Plugin::Obfs(ObfsOpts {
+                mode: get("obfs")
+                    .map(|m| ObfsMode::from_str(&m))
+                    .ok_or(Error::InvalidPlugin(s.to_string()))?
+                    .map_err(|_| Error::InvalidPlugin(s.to_string()))?,
+                host: get("obfs-host"),
+            })),
+            "v2ray-plugin" => Ok(Plugin::V2rayPlugin(V2rayPluginOpts {
+                mode: get("mode").unwrap_or("websocket".to_string()),
+                tls: flag("tls"),
+                host: get("host"),
+                path: get("path"),
+                mux: flag("mux"),
+                skip_cert_verify: flag("skip-cert-verify"),
+            })),
+            "shadow-tls" => {
+                let version = get("version")
+                    .map(|v| v.parse::<u8>())
+                    .transpose()
+                    .map_err(|_| Error::InvalidPlugin(s.to_string()))?
+                    .unwrap_or(2);
+                let password = get("password");
+                if !(1..=3).contains(&version) || (version > 1 && password.is_none()) {
+                    return Err(Error::InvalidPlugin(s.to_string()));
+                }
+
+                Ok(Plugin::ShadowTls(ShadowTlsOpts {
+                    host: get("host").ok_or(Error::InvalidPlugin(s.to_string()))?,
+                    password,
+                    version,
+                }))
+            }
+            _ => Err(Error::InvalidPlugin(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Plugin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = vec![];
+        match self {
+            Plugin::Obfs(opts) => {
+                parts.push("obfs-local".to_string());
+                parts.push(format!("obfs={}", opts.mode));
+                parts.extend(opts.host.iter().map(|h| format!("obfs-host={}", h)));
+            }
+            Plugin::V2rayPlugin(opts) => {
+                parts.push("v2ray-plugin".to_string());
+                parts.push(format!("mode={}", opts.mode));
+                if opts.tls {
+                    parts.push("tls".to_string());
+                }
+                parts.extend(opts.host.iter().map(|h| format!("host={}", h)));
+                parts.extend(opts.path.iter().map(|p| format!("path={}", p)));
+                if opts.mux {
+                    parts.push("mux".to_string());
+                }
+                if opts.skip_cert_verify {
+                    parts.push("skip-cert-verify".to_string());
+                }
+            }
+            Plugin::ShadowTls(opts) => {
+                parts.push("shadow-tls".to_string());
+                parts.push(format!("host={}", opts.host));
+                parts.extend(opts.password.iter().map(|p| format!("password={}", p)));
+                parts.push(format!("version={}", opts.version));
+            }
+        }
+
+        write!(f, "{}", parts.join(";"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_obfs() {
+        let plugin = Plugin::from_str("obfs-local;obfs=http;obfs-host=example.com").unwrap();
+
+        assert_eq!(
+            plugin,
+            Plugin::Obfs(ObfsOpts {
+                mode: ObfsMode::Http,
+                host: Some("example.com".to_string()),
+            })
+        );
+    }
+
+    #[test]
+    fn test_v2ray_plugin() {
+        let plugin =
+            Plugin::from_str("v2ray-plugin;mode=websocket;tls;host=example.com;path=/ws").unwrap();
+
+        assert_eq!(
+            plugin,
+            Plugin::V2rayPlugin(V2rayPluginOpts {
+                mode: "websocket".to_string(),
+                tls: true,
+                host: Some("example.com".to_string()),
+                path: Some("/ws".to_string()),
+                mux: false,
+                skip_cert_verify: false,
+            })
+        );
+    }
+
+    #[test]
+    fn test_shadow_tls() {
+        let plugin =
+            Plugin::from_str("shadow-tls;host=example.com;password=pass;version=3").unwrap();
+
+        assert_eq!(
+            plugin,
+            Plugin::ShadowTls(ShadowTlsOpts {
+                host: "example.com".to_string(),
+                password: Some("pass".to_string()),
+                version: 3,
+            })
+        );
+        assert!(Plugin::from_str("shadow-tls;host=example.com;version=3").is_err());
+    }
+
+    #[test]
+    fn test_display() {
+        for s in [
+            "obfs-local;obfs=http;obfs-host=example.com",
+            "v2ray-plugin;mode=websocket;tls;host=example.com;path=/ws;mux",
+            "shadow-tls;host=example.com;password=pass;version=3",
+        ] {
+            assert_eq!(Plugin::from_str(s).unwrap().to_string(), s);
+        }
+    }
+
+    #[test]
+    fn test_serialize() {
+        let plugin = Plugin::from_str("obfs-local;obfs=tls;obfs-host=example.com").unwrap();
+
+        let yaml = serde_yaml::to_string(&plugin).unwrap();
+
+        assert_eq!(
+            yaml,
+            "plugin: obfs\nplugin-opts:\n  mode: tls\n  host: example.com\n"
+        );
+    }
+}
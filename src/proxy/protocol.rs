@@ -3,23 +3,28 @@ use std::collections::HashMap;
 use crate::util::is_false;
 use crate::{error::Error, util::get_query};
 use serde::{ser::SerializeStruct, Deserialize, Deserializer, Serialize};
-#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+
+// every field is optional in clash profiles, trojan and hysteria2 call the
+// server name `sni`
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
 #[serde(rename_all = "kebab-case")]
 pub struct TLS {
-    #[serde(skip_serializing_if = "is_false")]
+    #[serde(default, skip_serializing_if = "is_false")]
     pub tls: bool,
-    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub alpn: Vec<String>,
-    #[serde(rename = "servername")]
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "servername", alias = "sni")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub server_name: Option<String>,
-    #[serde(skip_serializing_if = "is_false")]
+    #[serde(default, skip_serializing_if = "is_false")]
     pub skip_cert_verify: bool,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_fingerprint: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub reality_opts: Option<RealityOpts>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
 #[serde(rename_all = "kebab-case")]
 pub struct RealityOpts {
     pub public_key: String,
@@ -29,24 +34,49 @@ pub struct RealityOpts {
 impl TryFrom<url::Url> for TLS {
     type Error = Error;
     fn try_from(value: url::Url) -> Result<Self, Self::Error> {
-        let security = get_query("security", &value).map(String::from);
+        TLS::from_query(&value)?.ok_or(Error::InvalidTLS)
+    }
+}
+
+// the keys clients use for skipping certificate verification
+const INSECURE_KEYS: &[&str] = &["allowInsecure", "insecure", "skip_cert_verify"];
+
+impl TLS {
+    /// The tls settings of a share link, `None` when its `security` is neither
+    /// `tls` nor `reality`. A malformed value fails instead of dropping tls.
+    pub fn from_query(value: &url::Url) -> Result<Option<Self>, Error> {
+        let security = get_query("security", value);
+        TLS::from_security(value, security.as_deref())
+    }
 
-        let server_name = get_query("sni", &value).map(String::from);
-        let skip_cert_verify = get_query("skip_cert_verify", &value)
-            .map(|s| s.parse().unwrap())
-            .unwrap_or(false);
+    /// For protocols that always use tls, like hysteria2, whose links have no
+    /// `security` key.
+    pub fn from_query_implied(value: &url::Url) -> Result<Self, Error> {
+        let security = match get_query("security", value).as_deref() {
+            Some("reality") => "reality",
+            _ => "tls",
+        };
+        Ok(TLS::from_security(value, Some(security))?.unwrap_or_default())
+    }
+
+    fn from_security(value: &url::Url, security: Option<&str>) -> Result<Option<Self>, Error> {
+        let server_name = get_query("sni", value);
+        let skip_cert_verify = insecure_query(value)?;
 
-        let alpn = match value.query_pairs().filter(|(k, _)| k == "alpn").next() {
+        let client_fingerprint = get_query("fp", value);
+
+        let alpn = match value.query_pairs().find(|(k, _)| k == "alpn") {
             Some((_, v)) => v.split(',').map(|s| s.to_string()).collect(),
             None => vec![],
         };
 
-        match security.as_ref().map(|s| s.as_str()) {
+        let tls = match security {
             Some("tls") => Some(TLS {
                 server_name,
                 skip_cert_verify,
                 alpn,
                 tls: true,
+                client_fingerprint,
                 reality_opts: None,
             }),
             Some("reality") => Some(TLS {
@@ -54,18 +84,38 @@ impl TryFrom<url::Url> for TLS {
                 skip_cert_verify,
                 alpn,
                 tls: true,
+                client_fingerprint,
                 reality_opts: Some(RealityOpts {
-                    public_key: get_query("pbk", &value).unwrap_or_default(),
-                    short_id: get_query("sid", &value).unwrap_or_default(),
+                    public_key: get_query("pbk", value).unwrap_or_default(),
+                    short_id: get_query("sid", value).unwrap_or_default(),
                 }),
             }),
             _ => None,
-        }
-        .ok_or(Error::InvalidTLS)
+        };
+
+        Ok(tls)
+    }
+}
+
+/// Whether a share link skips certificate verification, under any of the
+/// keys clients use for it.
+pub fn insecure_query(value: &url::Url) -> Result<bool, Error> {
+    Ok(INSECURE_KEYS
+        .iter()
+        .find_map(|key| get_query(key, value).map(|v| parse_flag(key, &v)))
+        .transpose()?
+        .unwrap_or(false))
+}
+
+fn parse_flag(key: &str, value: &str) -> Result<bool, Error> {
+    match value {
+        "1" | "true" => Ok(true),
+        "0" | "false" => Ok(false),
+        _ => Err(Error::InvalidValue(key.to_string(), value.to_string())),
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Network {
     Http {
         method: String,
@@ -127,7 +177,7 @@ impl Serialize for Network {
                 state.serialize_field(
                     "grpc-opts",
                     &serde_json::json!({
-                        "grpc_service_name": grpc_service_name
+                        "grpc-service-name": grpc_service_name
                     }),
                 )?;
                 state.end()
@@ -144,7 +194,7 @@ impl Serialize for Network {
                         serde_json::json!({
                             "path": path,
                             "headers": headers,
-                            "max_early_data": v
+                            "max-early-data": v
                         })
                     }
                     None => {
@@ -194,16 +244,14 @@ impl<'de> Deserialize<'de> for Network {
                     .and_then(|v| v.as_object())
                     .unwrap_or(&serde_json::Map::new())
                     .iter()
-                    .map(|(k, v)| {
-                        (
-                            k.clone(),
-                            v.as_array()
-                                .unwrap()
-                                .iter()
-                                .filter_map(|s| s.as_str())
-                                .map(|s| s.to_string())
-                                .collect(),
-                        )
+                    .filter_map(|(k, v)| {
+                        let values = v
+                            .as_array()?
+                            .iter()
+                            .filter_map(|s| s.as_str())
+                            .map(|s| s.to_string())
+                            .collect();
+                        Some((k.clone(), values))
                     })
                     .collect();
 
@@ -234,25 +282,22 @@ impl<'de> Deserialize<'de> for Network {
 
                 Ok(Network::H2 { host, path })
             }
+            // clash fills in what the opts of grpc and ws leave out
             Some("grpc") => {
-                let opts = map
-                    .get("grpc-opts")
-                    .ok_or_else(|| serde::de::Error::missing_field("grpc-opts"))?;
+                let opts = map.get("grpc-opts").unwrap_or(&serde_json::Value::Null);
                 let grpc_service_name = opts
-                    .get("grpc_service_name")
+                    .get("grpc-service-name")
                     .and_then(|v| v.as_str())
                     .map(|s| s.to_string());
 
                 Ok(Network::Grpc { grpc_service_name })
             }
             Some("ws") => {
-                let opts = map
-                    .get("ws-opts")
-                    .ok_or_else(|| serde::de::Error::missing_field("ws-opts"))?;
+                let opts = map.get("ws-opts").unwrap_or(&serde_json::Value::Null);
                 let path = opts
                     .get("path")
                     .and_then(|v| v.as_str())
-                    .ok_or_else(|| serde::de::Error::missing_field("path"))?
+                    .unwrap_or("/")
                     .to_string();
 
                 let headers = opts
@@ -260,11 +305,11 @@ impl<'de> Deserialize<'de> for Network {
                     .and_then(|v| v.as_object())
                     .unwrap_or(&serde_json::Map::new())
                     .iter()
-                    .map(|(k, v)| (k.clone(), v.as_str().unwrap().to_string()))
+                    .filter_map(|(k, v)| Some((k.clone(), v.as_str()?.to_string())))
                     .collect();
 
                 let max_early_data = opts
-                    .get("max_early_data")
+                    .get("max-early-data")
                     .and_then(|v| v.as_u64())
                     .map(|v| v as usize);
 
@@ -274,7 +319,7 @@ impl<'de> Deserialize<'de> for Network {
                     max_early_data,
                 })
             }
-            _ => Err(serde::de::Error::custom(format!("unknown network type"))),
+            _ => Err(serde::de::Error::custom("unknown network type")),
         }
     }
 }
@@ -286,22 +331,46 @@ impl TryFrom<url::Url> for Network {
 
         network_type
             .clone()
-            .map(|s| match s.as_str() {
+            .and_then(|s| match s.as_str() {
                 "grpc" => Some(Network::Grpc {
                     grpc_service_name: get_query("serviceName", &value),
                 }),
+                "h2" | "http" => Some(Network::H2 {
+                    host: get_query("host", &value)
+                        .map(|h| h.split(',').map(|s| s.to_string()).collect())
+                        .unwrap_or_default(),
+                    path: get_query("path", &value).unwrap_or_default(),
+                }),
+                "tcp" if get_query("headerType", &value).as_deref() == Some("http") => {
+                    Some(Network::Http {
+                        method: "GET".to_string(),
+                        path: get_query("path", &value)
+                            .unwrap_or("/".to_string())
+                            .split(',')
+                            .map(|s| s.to_string())
+                            .collect(),
+                        headers: get_query("host", &value)
+                            .map(|h| {
+                                HashMap::from([(
+                                    "Host".to_string(),
+                                    h.split(',').map(|s| s.to_string()).collect(),
+                                )])
+                            })
+                            .unwrap_or_default(),
+                    })
+                }
                 "ws" => Some(Network::Ws {
                     path: get_query("path", &value).unwrap_or_default(),
                     headers: {
                         let mut headers = std::collections::HashMap::new();
-                        match get_query("sni", &value).map(String::from) {
-                            Some(sni) => {
-                                headers.insert("Host".to_string(), sni);
+                        match get_query("host", &value).or(get_query("sni", &value)) {
+                            Some(host) => {
+                                headers.insert("Host".to_string(), host);
                             }
                             None => {
                                 headers.insert(
                                     "Host".to_string(),
-                                    value.host_str().unwrap().to_string(),
+                                    value.host_str().unwrap_or_default().to_string(),
                                 );
                             }
                         }
@@ -311,9 +380,87 @@ impl TryFrom<url::Url> for Network {
                 }),
                 _ => None,
             })
-            .flatten()
             .ok_or(Error::InvalidNetwork(
-                network_type.clone().unwrap_or_default().into(),
+                network_type.clone().unwrap_or_default(),
             ))
     }
 }
+
+impl TLS {
+    /// Query of a share link, `insecure` is the key the scheme uses for
+    /// skipping certificate verification.
+    pub fn to_query(&self, insecure: &'static str) -> Vec<(&'static str, String)> {
+        let mut query = vec![];
+        if !self.tls {
+            return query;
+        }
+
+        match &self.reality_opts {
+            Some(reality) => {
+                query.push(("security", "reality".to_string()));
+                query.push(("pbk", reality.public_key.clone()));
+                query.push(("sid", reality.short_id.clone()));
+            }
+            None => query.push(("security", "tls".to_string())),
+        }
+        if let Some(sni) = &self.server_name {
+            query.push(("sni", sni.clone()));
+        }
+        if !self.alpn.is_empty() {
+            query.push(("alpn", self.alpn.join(",")));
+        }
+        if let Some(fp) = &self.client_fingerprint {
+            query.push(("fp", fp.clone()));
+        }
+        if self.skip_cert_verify {
+            query.push((insecure, "1".to_string()));
+        }
+
+        query
+    }
+}
+
+impl Network {
+    pub fn to_query(&self) -> Vec<(&'static str, String)> {
+        match self {
+            Network::Http { path, headers, .. } => {
+                let mut query = vec![
+                    ("type", "tcp".to_string()),
+                    ("headerType", "http".to_string()),
+                    ("path", path.join(",")),
+                ];
+                if let Some(host) = headers.get("Host") {
+                    query.push(("host", host.join(",")));
+                }
+                query
+            }
+            Network::H2 { host, path } => {
+                let mut query = vec![("type", "h2".to_string())];
+                if !host.is_empty() {
+                    query.push(("host", host.join(",")));
+                }
+                if !path.is_empty() {
+                    query.push(("path", path.clone()));
+                }
+                query
+            }
+            Network::Grpc { grpc_service_name } => {
+                let mut query = vec![("type", "grpc".to_string())];
+                if let Some(service_name) = grpc_service_name {
+                    query.push(("serviceName", service_name.clone()));
+                }
+                query
+            }
+            Network::Ws { path, headers, .. } => {
+                let mut query = vec![("type", "ws".to_string())];
+                if !path.is_empty() {
+                    query.push(("path", path.clone()));
+                }
+                if let Some(host) = headers.get("Host") {
+                    query.push(("host", host.clone()));
+                }
+                query
+            }
+        }
+    }
+}
@@ -1,53 +1,296 @@
-use std::collections::HashMap;
+use serde::{Deserialize, Deserializer, Serialize};
 
-use serde::{Deserialize, Serialize};
+use super::{
+    common::{fragment_name, BaseProxy},
+    plugin::Plugin,
+};
+use crate::{
+    error::Error,
+    util::{decode_base64_string, get_query, percent_decode},
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
 
-use super::common::BaseProxy;
+pub const SUPPORTED_CIPHERS: &[&str] = &[
+    "aes-128-gcm",
+    "aes-192-gcm",
+    "aes-256-gcm",
+    "aes-128-cfb",
+    "aes-192-cfb",
+    "aes-256-cfb",
+    "aes-128-ctr",
+    "aes-192-ctr",
+    "aes-256-ctr",
+    "rc4-md5",
+    "chacha20-ietf",
+    "xchacha20",
+    "chacha20-ietf-poly1305",
+    "xchacha20-ietf-poly1305",
+    "2022-blake3-aes-128-gcm",
+    "2022-blake3-aes-256-gcm",
+    "2022-blake3-chacha20-poly1305",
+];
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct Shadowsocks {
     #[serde(flatten)]
     pub base: BaseProxy,
     pub cipher: String,
     pub password: String,
-    #[serde(default = "Default::default")]
-    pub udp: bool,
-    pub plugin: Option<String>,
-    pub plugin_opts: Option<HashMap<String, serde_yaml::Value>>,
+    #[serde(flatten, deserialize_with = "deserialize_plugin")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub plugin: Option<Plugin>,
 }
 
-// impl TryFrom<url::Url> for Shadowsocks {
-//     type Error = anyhow::Error;
-
-//     fn try_from(url: url::Url) -> Result<Self, Self::Error> {
-//         let base = BaseProxy::try_from(url)?;
-
-//         let mut cipher = None;
-//         let mut password = None;
-//         let mut udp = None;
-//         let mut plugin = None;
-//         let mut plugin_opts = None;
-
-//         for (key, value) in url.query_pairs() {
-//             match key.as_ref() {
-//                 "cipher" => cipher = Some(value.into_owned()),
-//                 "password" => password = Some(value.into_owned()),
-//                 "udp" => udp = Some(value.parse()?),
-//                 "plugin" => plugin = Some(value.into_owned()),
-//                 "plugin_opts" => {
-//                     plugin_opts = Some(serde_urlencoded::from_str(&value)?);
-//                 }
-//                 _ => {}
-//             }
-//         }
-
-//         Ok(Shadowsocks {
-//             base,
-//             cipher: cipher.ok_or_else(|| anyhow::anyhow!("missing cipher"))?,
-//             password: password.ok_or_else(|| anyhow::anyhow!("missing password"))?,
-//             udp: udp.unwrap_or(false),
-//             plugin,
-//             plugin_opts,
-//         })
-//     }
-// }
+// a flattened `Option` reads as `None` whenever the inner value fails, so a
+// plugin we do not know would be dropped instead of failing the proxy
+fn deserialize_plugin<'de, D>(deserializer: D) -> Result<Option<Plugin>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct Raw {
+        plugin: Option<String>,
+        #[serde(rename = "plugin-opts")]
+        plugin_opts: Option<serde_json::Value>,
+    }
+
+    let raw = Raw::deserialize(deserializer)?;
+    let Some(plugin) = raw.plugin else {
+        return Ok(None);
+    };
+
+    Plugin::deserialize(serde_json::json!({
+        "plugin": plugin,
+        "plugin-opts": raw.plugin_opts,
+    }))
+    .map(Some)
+    .map_err(|e| serde::de::Error::custom(format!("invalid plugin '{}': {}", plugin, e)))
+}
+
+// ss links come in two flavours:
+//   SIP002: ss://base64(method:password)@host:port/?plugin=...#name
+//   legacy: ss://base64(method:password@host:port)#name
+impl TryFrom<url::Url> for Shadowsocks {
+    type Error = Error;
+
+    fn try_from(url: url::Url) -> Result<Self, Self::Error> {
+        let plugin = get_query("plugin", &url)
+            .map(|p| p.parse::<Plugin>())
+            .transpose()?;
+
+        if url.username().is_empty() {
+            return Ok(Shadowsocks {
+                plugin,
+                ..Self::from_legacy(&url)?
+            });
+        }
+
+        let (cipher, password) = match url.password() {
+            // AEAD-2022 ciphers may be given as plain percent-encoded method:password
+            Some(password) => (percent_decode(url.username())?, percent_decode(password)?),
+            None => split_user_info(&decode_base64_string(&percent_decode(url.username())?)?)?,
+        };
+
+        Ok(Shadowsocks {
+            base: BaseProxy::try_from(url)?,
+            cipher: check_cipher(cipher)?,
+            password,
+            plugin,
+        })
+    }
+}
+
+impl Shadowsocks {
+    /// Export as a SIP002 link, AEAD-2022 keys are left as plain `method:password`
+    /// since they are base64 already.
+    pub fn to_url(&self) -> Result<String, Error> {
+        let query = self
+            .plugin
+            .iter()
+            .map(|p| ("plugin", p.to_string()))
+            .collect();
+
+        let url = match self.cipher.starts_with("2022-") {
+            true => self
+                .base
+                .to_url("ss", &self.cipher, Some(&self.password), query)?,
+            false => {
+                let user_info =
+                    URL_SAFE_NO_PAD.encode(format!("{}:{}", self.cipher, self.password));
+                self.base.to_url("ss", &user_info, None, query)?
+            }
+        };
+
+        Ok(url.to_string())
+    }
+
+    // the base64 payload is parsed as the host, a `/` in it starts the path
+    fn from_legacy(url: &url::Url) -> Result<Self, Error> {
+        let path = match url.path() {
+            "/" => "",
+            path => path,
+        };
+        let payload = percent_decode(&format!("{}{}", url.host_str().unwrap_or_default(), path))?;
+
+        let decoded = decode_base64_string(&payload)?;
+        let (user_info, server) = decoded
+            .rsplit_once('@')
+            .ok_or(Error::MissingField("server".to_string()))?;
+        let (cipher, password) = split_user_info(user_info)?;
+        let (host, port) = server
+            .rsplit_once(':')
+            .ok_or(Error::MissingField("port".to_string()))?;
+
+        Ok(Shadowsocks {
+            base: BaseProxy {
+                name: fragment_name(url),
+                server: host
+                    .trim_start_matches('[')
+                    .trim_end_matches(']')
+                    .to_string(),
+                port: port
+                    .parse()
+                    .map_err(|_| Error::InvalidPort(port.to_string()))?,
+                ip_version: None,
+                udp: false,
+                tags: vec![],
+            },
+            cipher: check_cipher(cipher)?,
+            password,
+            plugin: None,
+        })
+    }
+}
+
+fn split_user_info(user_info: &str) -> Result<(String, String), Error> {
+    user_info
+        .split_once(':')
+        .map(|(cipher, password)| (cipher.to_string(), password.to_string()))
+        .ok_or(Error::MissingField("password".to_string()))
+}
+
+fn check_cipher(cipher: String) -> Result<String, Error> {
+    let cipher = cipher.to_lowercase();
+    if SUPPORTED_CIPHERS.contains(&cipher.as_str()) {
+        Ok(cipher)
+    } else {
+        Err(Error::UnsupportedCipher(cipher))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::plugin::{ObfsMode, ObfsOpts};
+
+    #[test]
+    fn test_from_sip002_url() {
+        // base64url("aes-256-gcm:password") without padding
+        let url = "ss://YWVzLTI1Ni1nY206cGFzc3dvcmQ@hostname:8388#ss-proxy";
+
+        let ss = Shadowsocks::try_from(url::Url::parse(url).unwrap()).unwrap();
+
+        assert_eq!(ss.base.name, "ss-proxy");
+        assert_eq!(ss.base.server, "hostname");
+        assert_eq!(ss.base.port, 8388);
+        assert_eq!(ss.cipher, "aes-256-gcm");
+        assert_eq!(ss.password, "password");
+    }
+
+    #[test]
+    fn test_from_sip002_plain_user_info() {
+        let url = "ss://2022-blake3-aes-128-gcm:YctPZ6U7xPPcU%2Bgp3u%2B0tx%2FtRizJN9K8y%2BuKlW2qjlI%3D@hostname:8388#ss-2022";
+
+        let ss = Shadowsocks::try_from(url::Url::parse(url).unwrap()).unwrap();
+
+        assert_eq!(ss.cipher, "2022-blake3-aes-128-gcm");
+        assert_eq!(ss.password, "YctPZ6U7xPPcU+gp3u+0tx/tRizJN9K8y+uKlW2qjlI=");
+    }
+
+    #[test]
+    fn test_from_legacy_url() {
+        // base64("chacha20-ietf-poly1305:pass@1.2.3.4:8388")
+        let url = "ss://Y2hhY2hhMjAtaWV0Zi1wb2x5MTMwNTpwYXNzQDEuMi4zLjQ6ODM4OA==#legacy";
+
+        let ss = Shadowsocks::try_from(url::Url::parse(url).unwrap()).unwrap();
+
+        assert_eq!(ss.base.name, "legacy");
+        assert_eq!(ss.base.server, "1.2.3.4");
+        assert_eq!(ss.base.port, 8388);
+        assert_eq!(ss.cipher, "chacha20-ietf-poly1305");
+        assert_eq!(ss.password, "pass");
+
+        // base64("aes-256-gcm:???>@1.2.3.4:8388") has a `/` in it
+        let url = "ss://YWVzLTI1Ni1nY206Pz8/PkAxLjIuMy40OjgzODg=#slash";
+
+        let ss = Shadowsocks::try_from(url::Url::parse(url).unwrap()).unwrap();
+
+        assert_eq!(ss.base.name, "slash");
+        assert_eq!(ss.password, "???>");
+    }
+
+    #[test]
+    fn test_from_url_with_plugin() {
+        let url = "ss://YWVzLTI1Ni1nY206cGFzc3dvcmQ@hostname:8388/?plugin=v2ray-plugin%3Bmode%3Dwebsocket%3Btls%3Bhost%3Dexample.com%3Bpath%3D%2Fws#ss-ws";
+
+        let ss = Shadowsocks::try_from(url::Url::parse(url).unwrap()).unwrap();
+
+        assert!(matches!(
+            ss.plugin,
+            Some(Plugin::V2rayPlugin(ref opts)) if opts.tls && opts.path.as_deref() == Some("/ws")
+        ));
+
+        let yaml = serde_yaml::to_string(&ss).unwrap();
+        assert!(yaml.contains("plugin: v2ray-plugin\nplugin-opts:\n"));
+    }
+
+    #[test]
+    fn test_from_clash_plugin() {
+        let parse = |plugin: &str| {
+            serde_yaml::from_str::<Shadowsocks>(&format!(
+                "{{name: ss, server: hostname, port: 8388, udp: false, cipher: aes-256-gcm, password: password{}}}",
+                plugin
+            ))
+        };
+
+        assert_eq!(parse("").unwrap().plugin, None);
+        assert!(matches!(
+            parse(", plugin: obfs, plugin-opts: {mode: http, host: example.com}")
+                .unwrap()
+                .plugin,
+            Some(Plugin::Obfs(ObfsOpts {
+                mode: ObfsMode::Http,
+                ..
+            }))
+        ));
+        assert!(parse(", plugin: gost-plugin, plugin-opts: {mode: websocket}").is_err());
+        assert!(parse(", plugin: obfs, plugin-opts: {mode: quic}").is_err());
+    }
+
+    #[test]
+    fn test_to_url() {
+        for url in [
+            "ss://YWVzLTI1Ni1nY206cGFzc3dvcmQ@hostname:8388/?plugin=obfs-local%3Bobfs%3Dhttp%3Bobfs-host%3Dexample.com#ss%20proxy",
+            "ss://2022-blake3-aes-128-gcm:YctPZ6U7xPPcU%2Bgp3u%2B0tx%2FtRizJN9K8y%2BuKlW2qjlI%3D@hostname:8388#ss-2022",
+        ] {
+            let ss = Shadowsocks::try_from(url::Url::parse(url).unwrap()).unwrap();
+
+            let exported = ss.to_url().unwrap();
+            assert_eq!(
+                Shadowsocks::try_from(url::Url::parse(&exported).unwrap()).unwrap(),
+                ss
+            );
+        }
+    }
+
+    #[test]
+    fn test_unsupported_cipher() {
+        // base64url("foo:bar")
+        let url = "ss://Zm9vOmJhcg@hostname:8388#bad";
+
+        assert!(matches!(
+            Shadowsocks::try_from(url::Url::parse(url).unwrap()),
+            Err(Error::UnsupportedCipher(c)) if c == "foo"
+        ));
+    }
+}
@@ -2,10 +2,10 @@ use super::{
     common::BaseProxy,
     protocol::{Network, TLS},
 };
-use crate::error::Error;
+use crate::{error::Error, util::percent_decode};
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 #[serde(rename_all = "kebab-case")]
 pub struct Trojan {
     #[serde(flatten)]
@@ -24,9 +24,72 @@ impl TryFrom<url::Url> for Trojan {
     fn try_from(value: url::Url) -> Result<Self, Self::Error> {
         Ok(Trojan {
             base: BaseProxy::try_from(value.clone())?,
-            password: value.username().to_string(),
-            tls: TLS::try_from(value.clone()).ok(),
+            password: percent_decode(value.username())?,
+            tls: TLS::from_query(&value)?,
             opts: Network::try_from(value.clone()).ok(),
         })
     }
 }
+
+impl Trojan {
+    pub fn to_url(&self) -> Result<String, Error> {
+        let mut query = vec![];
+        query.extend(
+            self.tls
+                .iter()
+                .flat_map(|tls| tls.to_query("allowInsecure")),
+        );
+        query.extend(self.opts.iter().flat_map(Network::to_query));
+
+        Ok(self
+            .base
+            .to_url("trojan", &self.password, None, query)?
+            .to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_skip_cert_verify() {
+        let parse = |query: &str| {
+            let url = format!(
+                "trojan://password@hostname:443?security=tls&{}#trojan",
+                query
+            );
+            Trojan::try_from(url::Url::parse(&url).unwrap())
+        };
+        let skip = |query: &str| parse(query).unwrap().tls.unwrap().skip_cert_verify;
+
+        assert!(skip("allowInsecure=1"));
+        assert!(skip("insecure=true"));
+        assert!(skip("skip_cert_verify=1"));
+        assert!(!skip("allowInsecure=0"));
+        assert!(!skip("sni=example.com"));
+        assert!(parse("insecure=1")
+            .unwrap()
+            .to_url()
+            .unwrap()
+            .contains("allowInsecure=1"));
+        assert!(matches!(
+            parse("allowInsecure=yes"),
+            Err(Error::InvalidValue(key, value)) if key == "allowInsecure" && value == "yes"
+        ));
+    }
+
+    #[test]
+    fn test_to_url() {
+        let url = "trojan://p%40ss@hostname:443?security=tls&sni=example.com&alpn=h2&fp=chrome&type=grpc&serviceName=svc#trojan-proxy";
+
+        let trojan = Trojan::try_from(url::Url::parse(url).unwrap()).unwrap();
+        assert_eq!(trojan.password, "p@ss");
+
+        let exported = trojan.to_url().unwrap();
+        assert_eq!(
+            Trojan::try_from(url::Url::parse(&exported).unwrap()).unwrap(),
+            trojan
+        );
+    }
+}
@@ -7,15 +7,15 @@ use super::{
     protocol::{Network, TLS},
 };
 
-use crate::util::get_query;
+use crate::util::{get_query, percent_decode};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 #[serde(rename_all = "kebab-case")]
 pub struct Vless {
     #[serde(flatten)]
     pub base: BaseProxy,
     pub uuid: String,
-    #[serde(skip_serializing_if = "String::is_empty")]
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub flow: String,
     // pub packet_encoding: String,
     #[serde(flatten)]
@@ -31,12 +31,50 @@ impl TryFrom<url::Url> for Vless {
     fn try_from(value: url::Url) -> Result<Self, Self::Error> {
         Ok(Vless {
             base: BaseProxy::try_from(value.clone())?,
-            uuid: value.username().to_string(),
-            flow: get_query("flow".into(), &value).unwrap_or_default(),
+            uuid: percent_decode(value.username())?,
+            flow: get_query("flow", &value).unwrap_or_default(),
             // packet_encoding: None,
             network: Network::try_from(value.clone()).ok(),
 
-            tls: TLS::try_from(value.clone()).ok(),
+            tls: TLS::from_query(&value)?,
         })
     }
 }
+
+impl Vless {
+    pub fn to_url(&self) -> Result<String, Error> {
+        let mut query = vec![("encryption", "none".to_string())];
+        if !self.flow.is_empty() {
+            query.push(("flow", self.flow.clone()));
+        }
+        query.extend(
+            self.tls
+                .iter()
+                .flat_map(|tls| tls.to_query("allowInsecure")),
+        );
+        query.extend(self.network.iter().flat_map(Network::to_query));
+
+        Ok(self
+            .base
+            .to_url("vless", &self.uuid, None, query)?
+            .to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_url() {
+        let url = "vless://some-uuid@hostname:443?encryption=none&flow=xtls-rprx-vision&security=reality&sni=example.com&fp=chrome&pbk=public-key&sid=short-id&type=ws&path=%2Fws&host=cdn.example.com#vless-proxy";
+
+        let vless = Vless::try_from(url::Url::parse(url).unwrap()).unwrap();
+
+        let exported = vless.to_url().unwrap();
+        assert_eq!(
+            Vless::try_from(url::Url::parse(&exported).unwrap()).unwrap(),
+            vless
+        );
+    }
+}
@@ -1,29 +1,29 @@
-use core::str;
+use std::collections::HashMap;
 
-use super::{common::BaseProxy, protocol::Network};
-use crate::error::Error;
+use super::{
+    common::BaseProxy,
+    protocol::{Network, TLS},
+};
+use crate::{
+    error::Error,
+    util::{decode_base64_string, get_query},
+};
 use base64::{engine::general_purpose::STANDARD, Engine as _};
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{json, Value};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 #[serde(rename_all = "kebab-case")]
 pub struct Vmess {
     #[serde(flatten)]
     pub base: BaseProxy,
     pub uuid: String,
-    #[serde(rename = "alterId")]
+    #[serde(rename = "alterId", default)]
     pub alter_id: u16,
     pub cipher: Option<String>,
+    #[serde(flatten)]
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub udp: Option<bool>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub tls: Option<bool>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub skip_cert_verify: Option<bool>,
-    #[serde(rename = "servername")]
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub server_name: Option<String>,
+    pub tls: Option<TLS>,
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(flatten)]
     pub network: Option<Network>,
@@ -32,51 +32,414 @@ pub struct Vmess {
 impl TryFrom<url::Url> for Vmess {
     type Error = Error;
 
-    // vmess use base64 encode
+    // vmess links are either the v2rayN base64 json blob, or the standard
+    // vmess://uuid@host:port?type=ws&security=tls#name uri form
     fn try_from(value: url::Url) -> Result<Self, Self::Error> {
-        let byte_data = STANDARD
-            .decode(value.host().unwrap().to_string().as_bytes())
-            .unwrap();
-        let str_data = str::from_utf8(&byte_data).unwrap();
-        let value: Value = serde_json::from_str(str_data).unwrap();
+        if !value.username().is_empty() {
+            return Self::from_uri(value);
+        }
+
+        let payload = value
+            .as_str()
+            .trim_start_matches("vmess://")
+            .split('#')
+            .next()
+            .unwrap_or_default();
+
+        let json: Value = serde_json::from_str(&decode_base64_string(payload)?)?;
+
+        Vmess::try_from(json)
+    }
+}
+
+impl Vmess {
+    /// Export in the v2rayN base64 json format, which every vmess client can import.
+    pub fn to_url(&self) -> Result<String, Error> {
+        let mut value = json!({
+            "v": "2",
+            "ps": self.base.name,
+            "add": self.base.server,
+            "port": self.base.port.to_string(),
+            "id": self.uuid,
+            "aid": self.alter_id.to_string(),
+            "scy": self.cipher.as_deref().unwrap_or("auto"),
+            "net": "tcp",
+            "type": "none",
+        });
+
+        match &self.network {
+            Some(Network::Http { path, headers, .. }) => {
+                value["type"] = json!("http");
+                value["path"] = json!(path.join(","));
+                if let Some(host) = headers.get("Host") {
+                    value["host"] = json!(host.join(","));
+                }
+            }
+            Some(Network::H2 { host, path }) => {
+                value["net"] = json!("h2");
+                value["host"] = json!(host.join(","));
+                value["path"] = json!(path);
+            }
+            Some(Network::Grpc { grpc_service_name }) => {
+                value["net"] = json!("grpc");
+                if let Some(service_name) = grpc_service_name {
+                    value["path"] = json!(service_name);
+                }
+            }
+            Some(Network::Ws { path, headers, .. }) => {
+                value["net"] = json!("ws");
+                value["path"] = json!(path);
+                if let Some(host) = headers.get("Host") {
+                    value["host"] = json!(host);
+                }
+            }
+            None => {}
+        }
+
+        if let Some(tls) = self.tls.as_ref().filter(|t| t.tls) {
+            value["tls"] = json!("tls");
+            if let Some(sni) = &tls.server_name {
+                value["sni"] = json!(sni);
+            }
+            if !tls.alpn.is_empty() {
+                value["alpn"] = json!(tls.alpn.join(","));
+            }
+            if let Some(fp) = &tls.client_fingerprint {
+                value["fp"] = json!(fp);
+            }
+            if tls.skip_cert_verify {
+                value["allowInsecure"] = json!("1");
+            }
+        }
+
+        Ok(format!("vmess://{}", STANDARD.encode(value.to_string())))
+    }
+
+    fn from_uri(value: url::Url) -> Result<Self, Error> {
+        // no `type` (or plain tcp) is the only case without a network
+        let network = match Network::try_from(value.clone()) {
+            Ok(network) => Some(network),
+            Err(_) if matches!(get_query("type", &value).as_deref(), None | Some("tcp")) => None,
+            Err(e) => return Err(e),
+        };
+
+        // like the v2rayN json, the sni defaults to the host header
+        let tls = TLS::from_query(&value)?.map(|tls| TLS {
+            server_name: tls.server_name.or(get_query("host", &value)),
+            ..tls
+        });
+
+        Ok(Vmess {
+            base: BaseProxy::try_from(value.clone())?,
+            uuid: value.username().to_string(),
+            alter_id: get_query("alterId", &value)
+                .map(|s| parse_alter_id("alterId", &s))
+                .transpose()?
+                .unwrap_or(0),
+            cipher: Some(get_query("encryption", &value).unwrap_or("auto".to_string())),
+            tls,
+            network,
+        })
+    }
+}
+
+// v2rayN share format, see https://github.com/2dust/v2rayN/wiki/Description-of-VMess-share-link
+impl TryFrom<Value> for Vmess {
+    type Error = Error;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        let field = |key: &str| {
+            value
+                .get(key)
+                .and_then(|v| match v {
+                    Value::String(s) => Some(s.trim().to_string()),
+                    Value::Number(n) => Some(n.to_string()),
+                    _ => None,
+                })
+                .filter(|s| !s.is_empty())
+        };
+        let required = |key: &str| field(key).ok_or(Error::MissingField(key.to_string()));
+
+        let port = required("port")?;
+        let alter_id = field("aid").unwrap_or("0".to_string());
+
+        let host = field("host");
+        let path = field("path");
+
+        let tls = match field("tls").as_deref() {
+            Some("tls") => Some(TLS {
+                tls: true,
+                alpn: field("alpn")
+                    .map(|s| s.split(',').map(|s| s.to_string()).collect())
+                    .unwrap_or_default(),
+                server_name: field("sni").or(host.clone()),
+                skip_cert_verify: matches!(field("allowInsecure").as_deref(), Some("1" | "true")),
+                client_fingerprint: field("fp"),
+                reality_opts: None,
+            }),
+            _ => None,
+        };
+
+        let network = match field("net").as_deref() {
+            None | Some("tcp") => match field("type").as_deref() {
+                Some("http") => Some(Network::Http {
+                    method: "GET".to_string(),
+                    path: split_list(path.as_deref().unwrap_or("/")),
+                    headers: host
+                        .as_deref()
+                        .map(|h| HashMap::from([("Host".to_string(), split_list(h))]))
+                        .unwrap_or_default(),
+                }),
+                _ => None,
+            },
+            Some("ws") => Some(Network::Ws {
+                path: path.unwrap_or("/".to_string()),
+                headers: host
+                    .map(|h| HashMap::from([("Host".to_string(), h)]))
+                    .unwrap_or_default(),
+                max_early_data: None,
+            }),
+            Some("h2") | Some("http") => Some(Network::H2 {
+                host: host.as_deref().map(split_list).unwrap_or_default(),
+                path: path.unwrap_or("/".to_string()),
+            }),
+            Some("grpc") => Some(Network::Grpc {
+                grpc_service_name: path,
+            }),
+            Some(net) => return Err(Error::InvalidNetwork(net.to_string())),
+        };
 
         Ok(Vmess {
             base: BaseProxy {
-                name: value["ps"].as_str().unwrap().to_string(),
-                server: value["host"].as_str().unwrap().to_string(),
-                port: value["port"].as_u64().unwrap() as usize,
+                name: field("ps").unwrap_or_default(),
+                server: required("add")?,
+                port: port.parse().map_err(|_| Error::InvalidPort(port))?,
                 ip_version: None,
                 udp: false,
+                tags: vec![],
             },
-            uuid: value["id"].as_str().unwrap().to_string(),
-            alter_id: value["aid"].as_u64().unwrap() as u16,
-            cipher: Some("auto".into()),
-            udp: None,
-            tls: match value["tls"].as_str() {
-                Some("tls") => Some(true),
-                _ => None,
-            },
-            skip_cert_verify: None,
-            server_name: None,
-            network: match value["net"].as_str() {
-                Some("ws") => Some(Network::Ws {
-                    path: value["path"]
-                        .as_str()
-                        .map(|s| s.to_string())
-                        .unwrap_or("".into()),
-                    headers: {
-                        let mut headers = std::collections::HashMap::new();
-                        if let Some(host) = value["host"].as_str() {
-                            headers.insert("Host".into(), host.into());
-                        }
-                        headers
-                    }
-                    .into_iter()
-                    .collect(),
-                    max_early_data: None,
-                }),
-                _ => None,
-            },
+            uuid: required("id")?,
+            alter_id: parse_alter_id("aid", &alter_id)?,
+            cipher: Some(field("scy").unwrap_or("auto".to_string())),
+            tls,
+            network,
         })
     }
 }
+
+fn parse_alter_id(key: &str, value: &str) -> Result<u16, Error> {
+    value
+        .parse()
+        .map_err(|_| Error::InvalidValue(key.to_string(), value.to_string()))
+}
+
+fn split_list(s: &str) -> Vec<String> {
+    s.split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+
+    fn encode(json: Value) -> url::Url {
+        let encoded = URL_SAFE_NO_PAD.encode(json.to_string());
+        url::Url::parse(&format!("vmess://{}", encoded)).unwrap()
+    }
+
+    #[test]
+    fn test_from_url() {
+        let url = encode(serde_json::json!({
+            "v": "2",
+            "ps": "vmess-proxy",
+            "add": "server.example",
+            "port": "443",
+            "id": "some-uuid",
+            "aid": 0,
+            "scy": "aes-128-gcm",
+            "net": "ws",
+            "host": "cdn.example",
+            "path": "/ws",
+            "tls": "tls",
+            "sni": "sni.example",
+            "alpn": "h2,http/1.1",
+            "fp": "chrome"
+        }));
+
+        let vmess = Vmess::try_from(url).unwrap();
+
+        assert_eq!(vmess.base.name, "vmess-proxy");
+        assert_eq!(vmess.base.server, "server.example");
+        assert_eq!(vmess.base.port, 443);
+        assert_eq!(vmess.uuid, "some-uuid");
+        assert_eq!(vmess.alter_id, 0);
+        assert_eq!(vmess.cipher.as_deref(), Some("aes-128-gcm"));
+
+        let tls = vmess.tls.unwrap();
+        assert_eq!(tls.server_name.as_deref(), Some("sni.example"));
+        assert_eq!(tls.alpn, vec!["h2", "http/1.1"]);
+        assert_eq!(tls.client_fingerprint.as_deref(), Some("chrome"));
+
+        assert!(matches!(
+            vmess.network,
+            Some(Network::Ws { ref path, ref headers, .. })
+                if path == "/ws" && headers.get("Host").map(String::as_str) == Some("cdn.example")
+        ));
+    }
+
+    #[test]
+    fn test_tcp_http_header() {
+        let url = encode(serde_json::json!({
+            "add": "server.example",
+            "port": 80,
+            "id": "some-uuid",
+            "aid": "4",
+            "net": "tcp",
+            "type": "http",
+            "host": "a.example,b.example",
+            "path": "/"
+        }));
+
+        let vmess = Vmess::try_from(url).unwrap();
+
+        assert_eq!(vmess.base.port, 80);
+        assert_eq!(vmess.alter_id, 4);
+        assert!(vmess.tls.is_none());
+        assert!(matches!(
+            vmess.network,
+            Some(Network::Http { ref headers, .. }) if headers["Host"] == vec!["a.example", "b.example"]
+        ));
+    }
+
+    #[test]
+    fn test_grpc() {
+        let url = encode(serde_json::json!({
+            "add": "server.example",
+            "port": 443,
+            "id": "some-uuid",
+            "net": "grpc",
+            "path": "service"
+        }));
+
+        let vmess = Vmess::try_from(url).unwrap();
+
+        assert!(matches!(
+            vmess.network,
+            Some(Network::Grpc { grpc_service_name: Some(ref s) }) if s == "service"
+        ));
+    }
+
+    #[test]
+    fn test_from_uri() {
+        let url = "vmess://some-uuid@server.example:443?type=ws&security=tls&path=%2Fws&host=cdn.example&sni=sni.example&encryption=auto#vmess-uri";
+
+        let vmess = Vmess::try_from(url::Url::parse(url).unwrap()).unwrap();
+
+        assert_eq!(vmess.base.name, "vmess-uri");
+        assert_eq!(vmess.base.server, "server.example");
+        assert_eq!(vmess.base.port, 443);
+        assert_eq!(vmess.uuid, "some-uuid");
+        assert_eq!(vmess.alter_id, 0);
+        assert_eq!(vmess.cipher.as_deref(), Some("auto"));
+        assert_eq!(
+            vmess.tls.unwrap().server_name.as_deref(),
+            Some("sni.example")
+        );
+        assert!(matches!(
+            vmess.network,
+            Some(Network::Ws { ref path, ref headers, .. })
+                if path == "/ws" && headers.get("Host").map(String::as_str) == Some("cdn.example")
+        ));
+    }
+
+    #[test]
+    fn test_to_url() {
+        for json in [
+            serde_json::json!({
+                "ps": "vmess-ws",
+                "add": "server.example",
+                "port": 443,
+                "id": "some-uuid",
+                "net": "ws",
+                "host": "cdn.example",
+                "path": "/ws",
+                "tls": "tls",
+                "sni": "sni.example",
+                "alpn": "h2",
+                "fp": "chrome"
+            }),
+            serde_json::json!({
+                "ps": "vmess-http",
+                "add": "server.example",
+                "port": 80,
+                "id": "some-uuid",
+                "aid": 2,
+                "net": "tcp",
+                "type": "http",
+                "host": "a.example",
+                "path": "/a,/b"
+            }),
+            serde_json::json!({
+                "ps": "vmess-h2",
+                "add": "server.example",
+                "port": 443,
+                "id": "some-uuid",
+                "net": "h2",
+                "host": "a.example",
+                "path": "/h2",
+                "tls": "tls"
+            }),
+        ] {
+            let vmess = Vmess::try_from(encode(json)).unwrap();
+
+            let exported = vmess.to_url().unwrap();
+            assert_eq!(
+                Vmess::try_from(url::Url::parse(&exported).unwrap()).unwrap(),
+                vmess
+            );
+        }
+    }
+
+    #[test]
+    fn test_malformed() {
+        let url = url::Url::parse("vmess://not-base64!").unwrap();
+        assert!(Vmess::try_from(url).is_err());
+
+        let url = encode(serde_json::json!({ "add": "server.example", "port": 443 }));
+        assert!(matches!(Vmess::try_from(url), Err(Error::MissingField(f)) if f == "id"));
+
+        let url = encode(serde_json::json!({
+            "add": "server.example",
+            "port": "abc",
+            "id": "some-uuid"
+        }));
+        assert!(matches!(Vmess::try_from(url), Err(Error::InvalidPort(_))));
+
+        let url = encode(serde_json::json!({
+            "add": "server.example",
+            "port": 443,
+            "id": "some-uuid",
+            "aid": "x"
+        }));
+        assert!(
+            matches!(Vmess::try_from(url), Err(Error::InvalidValue(k, v)) if k == "aid" && v == "x")
+        );
+
+        let uri = |s: &str| Vmess::try_from(url::Url::parse(s).unwrap());
+        assert!(matches!(
+            uri("vmess://some-uuid@server.example:443?alterId=x#a"),
+            Err(Error::InvalidValue(k, _)) if k == "alterId"
+        ));
+        assert!(matches!(
+            uri("vmess://some-uuid@server.example:443?type=kcp#a"),
+            Err(Error::InvalidNetwork(n)) if n == "kcp"
+        ));
+        assert!(uri("vmess://some-uuid@server.example:443?type=tcp#a")
+            .unwrap()
+            .network
+            .is_none());
+    }
+}
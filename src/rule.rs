@@ -0,0 +1,262 @@
+use std::{collections::HashSet, fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+use serde_enum_str::{Deserialize_enum_str, Serialize_enum_str};
+
+use crate::error::Error;
+
+/// A clash rule, written the same way as in clash: `DOMAIN-SUFFIX,google.com,Proxy`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Rule {
+    pub rule_type: RuleType,
+    pub payload: Option<String>,
+    pub target: String,
+    pub no_resolve: bool,
+}
+
+#[derive(Deserialize_enum_str, Serialize_enum_str, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING-KEBAB-CASE")]
+pub enum RuleType {
+    Domain,
+    DomainSuffix,
+    DomainKeyword,
+    IpCidr,
+    IpCidr6,
+    #[serde(rename = "GEOIP")]
+    GeoIp,
+    ProcessName,
+    RuleSet,
+    Match,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct RuleProvider {
+    #[serde(rename = "type")]
+    pub provider_type: RuleProviderType,
+    pub behavior: Behavior,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<Format>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interval: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload: Option<Vec<String>>,
+}
+
+#[derive(Deserialize_enum_str, Serialize_enum_str, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RuleProviderType {
+    Http,
+    File,
+    Inline,
+}
+
+#[derive(Deserialize_enum_str, Serialize_enum_str, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Behavior {
+    Domain,
+    Ipcidr,
+    Classical,
+}
+
+#[derive(Deserialize_enum_str, Serialize_enum_str, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Format {
+    Yaml,
+    Text,
+    Mrs,
+}
+
+impl Rule {
+    /// Check the target against the known group / proxy names, and `RULE-SET`
+    /// payloads against the configured rule providers.
+    pub fn validate(
+        &self,
+        targets: &HashSet<&str>,
+        providers: &HashSet<&str>,
+    ) -> Result<(), Error> {
+        if !targets.contains(self.target.as_str()) {
+            return Err(Error::UnknownRuleTarget(
+                self.to_string(),
+                self.target.clone(),
+            ));
+        }
+        if self.rule_type == RuleType::RuleSet {
+            let provider = self.payload.as_deref().unwrap_or_default();
+            if !providers.contains(provider) {
+                return Err(Error::UnknownRuleTarget(
+                    self.to_string(),
+                    provider.to_string(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl RuleProvider {
+    pub fn validate(&self, name: &str) -> Result<(), Error> {
+        let valid = match self.provider_type {
+            RuleProviderType::Http => self.url.is_some(),
+            RuleProviderType::File => self.path.is_some(),
+            RuleProviderType::Inline => self.payload.is_some(),
+        };
+
+        match valid {
+            true => Ok(()),
+            false => Err(Error::InvalidRuleProvider(name.to_string())),
+        }
+    }
+}
+
+impl FromStr for Rule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(',').map(str::trim).collect();
+        let rule_type =
+            RuleType::from_str(parts[0]).map_err(|_| Error::InvalidRule(s.to_string()))?;
+
+        let (payload, target, options) = match (&rule_type, parts.as_slice()) {
+            (RuleType::Match, [_, target]) => (None, target, &[][..]),
+            (RuleType::Match, _) => return Err(Error::InvalidRule(s.to_string())),
+            (_, [_, payload, target, options @ ..]) if !payload.is_empty() => {
+                (Some(payload.to_string()), target, options)
+            }
+            _ => return Err(Error::InvalidRule(s.to_string())),
+        };
+
+        let no_resolve = match options {
+            [] => false,
+            ["no-resolve"] => true,
+            _ => return Err(Error::InvalidRule(s.to_string())),
+        };
+
+        Ok(Rule {
+            rule_type,
+            payload,
+            target: target.to_string(),
+            no_resolve,
+        })
+    }
+}
+
+impl TryFrom<String> for Rule {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Rule> for String {
+    fn from(value: Rule) -> Self {
+        value.to_string()
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.rule_type)?;
+        if let Some(payload) = &self.payload {
+            write!(f, ",{}", payload)?;
+        }
+        write!(f, ",{}", self.target)?;
+        if self.no_resolve {
+            write!(f, ",no-resolve")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        for s in [
+            "DOMAIN-SUFFIX,google.com,Proxy",
+            "DOMAIN-KEYWORD,ads,REJECT",
+            "IP-CIDR,192.168.0.0/16,DIRECT,no-resolve",
+            "GEOIP,CN,DIRECT",
+            "PROCESS-NAME,curl,Proxy",
+            "RULE-SET,reject,REJECT",
+            "MATCH,Proxy",
+        ] {
+            assert_eq!(Rule::from_str(s).unwrap().to_string(), s);
+        }
+
+        let rule = Rule::from_str("IP-CIDR, 10.0.0.0/8, DIRECT, no-resolve").unwrap();
+        assert_eq!(rule.rule_type, RuleType::IpCidr);
+        assert_eq!(rule.payload.as_deref(), Some("10.0.0.0/8"));
+        assert!(rule.no_resolve);
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        for s in [
+            "DOMAIN-SUFFIX,google.com",
+            "MATCH,google.com,Proxy",
+            "NOT-A-RULE,foo,Proxy",
+            "GEOIP,CN,DIRECT,resolve",
+        ] {
+            assert!(
+                matches!(Rule::from_str(s), Err(Error::InvalidRule(_))),
+                "{}",
+                s
+            );
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let targets = HashSet::from(["Proxy", "DIRECT"]);
+        let providers = HashSet::from(["ads"]);
+
+        assert!(Rule::from_str("MATCH,Proxy")
+            .unwrap()
+            .validate(&targets, &providers)
+            .is_ok());
+        assert!(matches!(
+            Rule::from_str("MATCH,US").unwrap().validate(&targets, &providers),
+            Err(Error::UnknownRuleTarget(_, t)) if t == "US"
+        ));
+        assert!(matches!(
+            Rule::from_str("RULE-SET,tracking,DIRECT").unwrap().validate(&targets, &providers),
+            Err(Error::UnknownRuleTarget(_, t)) if t == "tracking"
+        ));
+    }
+
+    #[test]
+    fn test_rule_provider() {
+        let provider: RuleProvider = toml::from_str(
+            r#"
+            type = "inline"
+            behavior = "domain"
+            payload = ["+.example.com"]
+            "#,
+        )
+        .unwrap();
+        assert!(provider.validate("inline").is_ok());
+
+        let provider: RuleProvider = toml::from_str(
+            r#"
+            type = "http"
+            behavior = "classical"
+            path = "./rules.yaml"
+            "#,
+        )
+        .unwrap();
+        assert!(matches!(
+            provider.validate("remote"),
+            Err(Error::InvalidRuleProvider(_))
+        ));
+    }
+}
@@ -0,0 +1,549 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    config::Config,
+    error::{EntryError, Error},
+    profile::Userinfo,
+    proxy::Proxy,
+    upstream::Cache,
+    util::mask_url,
+};
+
+/// A parsed config together with the proxies built from it.
+#[derive(Debug)]
+pub struct Snapshot {
+    pub config: Config,
+    pub proxies: HashMap<String, Vec<Proxy>>,
+    /// Base clash profile the generated one is merged into.
+    pub template: Option<serde_yaml::Value>,
+    /// The configured userinfo added up with the ones of the upstreams.
+    pub userinfo: Option<Userinfo>,
+    /// Enabled entries and upstream entries that did not parse and were left
+    /// out.
+    pub failures: Vec<EntryError>,
+}
+
+impl Snapshot {
+    /// Load the config at `path` and its upstreams through `cache`, a relative
+    /// template path is resolved against the directory of the config file.
+    pub async fn load(path: &Path, cache: &Cache) -> Result<Self, Error> {
+        let config = Config::from_file(&path.to_string_lossy())?;
+        config.validate()?;
+
+        let upstreams = fetch_upstreams(&config, cache).await;
+        let mut snapshot = Snapshot::new(config, upstreams);
+
+        if let Some(template) = template_path(path, &snapshot.config) {
+            let template = std::fs::read_to_string(template).map_err(Error::Io)?;
+            snapshot.template = Some(serde_yaml::from_str(&template)?);
+        }
+        snapshot.validate_rules()?;
+
+        Ok(snapshot)
+    }
+
+    /// Rules may point at the parsed proxies and at the proxies and groups of
+    /// the template.
+    fn validate_rules(&self) -> Result<(), Error> {
+        let template = |key: &'static str| {
+            self.template
+                .iter()
+                .filter_map(move |template| template[key].as_sequence())
+                .flatten()
+                .filter_map(|item| item["name"].as_str())
+        };
+
+        self.config.validate_rules(
+            self.proxies
+                .values()
+                .flatten()
+                .filter(|p| !p.is_reference())
+                .map(Proxy::name)
+                .chain(template("proxies"))
+                .chain(template("proxy-groups")),
+        )
+    }
+
+    /// Build the proxies of each group from its entries, followed by the ones
+    /// the upstreams provided for it, `config` has to be validated already.
+    fn new(config: Config, upstreams: Upstreams) -> Self {
+        let Upstreams {
+            proxies: mut upstreams,
+            userinfo,
+            failures: upstream_failures,
+        } = upstreams;
+        let mut failures = vec![];
+        let mut proxies: HashMap<String, Vec<Proxy>> = HashMap::new();
+        for (key, group) in &config.groups {
+            let members = proxies.entry(key.clone()).or_default();
+            for item in group.entries().iter().filter(|item| item.enabled()) {
+                match Proxy::try_from(item) {
+                    Ok(proxy) => members.push(proxy),
+                    Err(e) => failures.push(EntryError {
+                        group: key.clone(),
+                        entry: mask_url(item.url()),
+                        error: e.to_string(),
+                    }),
+                }
+            }
+        }
+
+        // an upstream proxy whose name is taken is left out, the same proxy can
+        // still be in several groups
+        let mut taken: HashMap<String, Proxy> = proxies
+            .values()
+            .flatten()
+            .filter(|p| !p.is_reference())
+            .map(|p| (p.name().to_string(), p.clone()))
+            .collect();
+        let mut groups: Vec<&String> = config.groups.keys().collect();
+        groups.sort();
+        for group in groups {
+            for proxy in upstreams.remove(group).unwrap_or_default() {
+                if config.name_taken(&taken, &proxy) {
+                    let error = Error::DuplicateName(group.clone(), proxy.name().to_string());
+                    eprintln!("left out upstream proxy: {}", error);
+                    failures.push(EntryError {
+                        group: group.clone(),
+                        entry: proxy.name().to_string(),
+                        error: error.to_string(),
+                    });
+                    continue;
+                }
+                taken.insert(proxy.name().to_string(), proxy.clone());
+                proxies.entry(group.clone()).or_default().push(proxy);
+            }
+        }
+        failures.extend(upstream_failures);
+        failures.sort_by(|a, b| a.group.cmp(&b.group));
+
+        let userinfo = config
+            .profile
+            .userinfo
+            .iter()
+            .cloned()
+            .chain(userinfo)
+            .reduce(Userinfo::merge);
+
+        Snapshot {
+            config,
+            proxies,
+            template: None,
+            userinfo,
+            failures,
+        }
+    }
+
+    /// In strict mode, fail when an entry of one of the `visible` groups did
+    /// not parse.
+    pub fn check(&self, visible: impl Fn(&str) -> bool) -> Result<(), Error> {
+        let failures: Vec<EntryError> = self
+            .failures
+            .iter()
+            .filter(|f| visible(&f.group))
+            .cloned()
+            .collect();
+
+        match self.config.strict && !failures.is_empty() {
+            true => Err(Error::InvalidEntries(failures)),
+            false => Ok(()),
+        }
+    }
+}
+
+/// What the upstreams provided for a snapshot.
+#[derive(Debug, Default)]
+struct Upstreams {
+    /// Proxies per group, in upstream name order.
+    proxies: HashMap<String, Vec<Proxy>>,
+    userinfo: Option<Userinfo>,
+    /// Skipped upstream entries, once for every group of their upstream.
+    failures: Vec<EntryError>,
+}
+
+/// Fetch every upstream concurrently and collect their proxies per group, their
+/// aggregated userinfo and the entries they skipped, which are logged. An
+/// upstream that fails with nothing cached is logged and left out.
+async fn fetch_upstreams(config: &Config, cache: &Cache) -> Upstreams {
+    let mut tasks = tokio::task::JoinSet::new();
+    for (name, upstream) in config.upstreams.clone() {
+        let cache = cache.clone();
+        tasks.spawn(async move {
+            let cached = cache.load(&name, &upstream).await;
+            let proxies = cached.and_then(|c| Ok((upstream.proxies(&c.body)?, c.userinfo())));
+            (name, upstream, proxies)
+        });
+    }
+
+    let mut fetched = vec![];
+    while let Some(joined) = tasks.join_next().await {
+        match joined {
+            Ok((name, upstream, Ok((parsed, userinfo)))) => {
+                fetched.push((name, upstream, parsed, userinfo))
+            }
+            Ok((name, _, Err(e))) => eprintln!("failed to fetch upstream {}: {}", name, e),
+            // a panicking fetch only loses its own upstream
+            Err(e) => eprintln!("failed to fetch an upstream: {}", e),
+        }
+    }
+    fetched.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut upstreams = Upstreams::default();
+    for (name, upstream, parsed, info) in fetched {
+        for skipped in &parsed.skipped {
+            eprintln!(
+                "skipped entry {} of upstream {}: {}",
+                skipped.entry, name, skipped.error
+            );
+        }
+        for group in &upstream.groups {
+            upstreams
+                .proxies
+                .entry(group.clone())
+                .or_default()
+                .extend(parsed.proxies.iter().cloned());
+            upstreams
+                .failures
+                .extend(parsed.skipped.iter().map(|skipped| EntryError {
+                    group: group.clone(),
+                    entry: format!("{}: {}", name, skipped.entry),
+                    error: skipped.error.clone(),
+                }));
+        }
+        upstreams.userinfo = upstreams
+            .userinfo
+            .into_iter()
+            .chain(info)
+            .reduce(Userinfo::merge);
+    }
+
+    upstreams
+}
+
+fn template_path(path: &Path, config: &Config) -> Option<PathBuf> {
+    config
+        .template
+        .as_ref()
+        .map(|template| path.parent().unwrap_or(Path::new(".")).join(template))
+}
+
+impl TryFrom<Config> for Snapshot {
+    type Error = Error;
+
+    fn try_from(config: Config) -> Result<Self, Self::Error> {
+        config.validate()?;
+
+        let snapshot = Snapshot::new(config, Upstreams::default());
+        snapshot.validate_rules()?;
+        Ok(snapshot)
+    }
+}
+
+/// Shared between handlers, holds the last config that parsed successfully.
+#[derive(Clone, Debug)]
+pub struct AppState {
+    path: PathBuf,
+    cache: Cache,
+    snapshot: Arc<RwLock<Arc<Snapshot>>>,
+}
+
+impl AppState {
+    pub async fn load(path: impl Into<PathBuf>, cache: Cache) -> Result<Self, Error> {
+        let path = path.into();
+        let snapshot = Snapshot::load(&path, &cache).await?;
+
+        Ok(AppState {
+            path,
+            cache,
+            snapshot: Arc::new(RwLock::new(Arc::new(snapshot))),
+        })
+    }
+
+    pub fn snapshot(&self) -> Arc<Snapshot> {
+        self.snapshot
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Reparse the config file, on error the current snapshot is kept.
+    pub async fn reload(&self) -> Result<(), Error> {
+        let snapshot = Snapshot::load(&self.path, &self.cache).await?;
+
+        *self.snapshot.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(snapshot);
+        Ok(())
+    }
+
+    /// Poll the modification time of the config file (and template) and reload
+    /// when it changes.
+    pub fn watch(&self, interval: Duration) -> tokio::task::JoinHandle<()> {
+        let state = self.clone();
+
+        tokio::spawn(async move {
+            let mut last_modified = state.modified();
+            let mut ticker = tokio::time::interval(interval);
+
+            loop {
+                ticker.tick().await;
+
+                let modified = state.modified();
+                if modified == last_modified {
+                    continue;
+                }
+                last_modified = modified;
+
+                match state.reload().await {
+                    Ok(()) => eprintln!("reloaded config from {}", state.path.display()),
+                    Err(e) => eprintln!(
+                        "failed to reload config from {}, keeping the last good one: {}",
+                        state.path.display(),
+                        e
+                    ),
+                }
+            }
+        })
+    }
+
+    /// Rebuild the snapshot whenever one of the upstreams outlived its max-age,
+    /// so requests are always served from the cache.
+    pub fn refresh(&self, interval: Duration) -> tokio::task::JoinHandle<()> {
+        let state = self.clone();
+
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+
+            loop {
+                ticker.tick().await;
+
+                let snapshot = state.snapshot();
+                let stale = snapshot
+                    .config
+                    .upstreams
+                    .iter()
+                    .any(|(name, upstream)| state.cache.is_stale(name, upstream));
+                if !stale {
+                    continue;
+                }
+
+                if let Err(e) = state.reload().await {
+                    eprintln!(
+                        "failed to refresh upstreams, keeping the last good ones: {}",
+                        e
+                    );
+                }
+            }
+        })
+    }
+
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        let mut paths = vec![self.path.clone()];
+        paths.extend(template_path(&self.path, &self.snapshot().config));
+
+        paths
+            .iter()
+            .map(|p| std::fs::metadata(p).and_then(|m| m.modified()).ok())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_config(path: &PathBuf, url: &str) {
+        std::fs::write(path, format!("[groups]\ngroup = [\"{}\"]\n", url)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reload_keeps_last_good() {
+        let path =
+            std::env::temp_dir().join(format!("sub-provider-state-{}.toml", std::process::id()));
+        write_config(&path, "trojan://password@hostname:443#a");
+
+        let state = AppState::load(&path, Cache::default()).await.unwrap();
+        assert_eq!(state.snapshot().proxies["group"][0].name(), "a");
+
+        write_config(&path, "trojan://password@hostname:443#b");
+        state.reload().await.unwrap();
+        assert_eq!(state.snapshot().proxies["group"][0].name(), "b");
+
+        std::fs::write(&path, "groups = ").unwrap();
+        assert!(state.reload().await.is_err());
+        assert_eq!(state.snapshot().proxies["group"][0].name(), "b");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_template_relative_to_config() {
+        let dir =
+            std::env::temp_dir().join(format!("sub-provider-template-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("base.yaml"), "mixed-port: 7893\n").unwrap();
+        std::fs::write(
+            dir.join("config.toml"),
+            "template = \"base.yaml\"\n[groups]\ngroup = []\n",
+        )
+        .unwrap();
+
+        let state = AppState::load(dir.join("config.toml"), Cache::default())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            state.snapshot().template.as_ref().unwrap()["mixed-port"],
+            serde_yaml::Value::from(7893)
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_strict() {
+        let config = |strict: bool| -> Config {
+            toml::from_str(&format!(
+                r#"
+                strict = {}
+                [groups]
+                HK = ["trojan://password@hk.example.com:443?udp=yes#HK", "trojan://password@hk.example.com:443#ok"]
+                US = ["trojan://password@us.example.com:443#US 01"]
+                "#,
+                strict
+            ))
+            .unwrap()
+        };
+
+        let snapshot = Snapshot::try_from(config(false)).unwrap();
+        assert_eq!(snapshot.proxies["HK"].len(), 1);
+        assert_eq!(snapshot.failures.len(), 1);
+        assert_eq!(
+            snapshot.failures[0].entry,
+            "trojan://***@hk.example.com:443#HK"
+        );
+        assert!(snapshot.check(|_| true).is_ok());
+
+        let snapshot = Snapshot::try_from(config(true)).unwrap();
+        assert!(matches!(
+            snapshot.check(|_| true),
+            Err(Error::InvalidEntries(entries)) if entries.len() == 1
+        ));
+        assert!(snapshot.check(|g| g == "US").is_ok());
+
+        // entries an upstream skipped count as well
+        let upstreams = Upstreams {
+            failures: vec![EntryError {
+                group: "US".to_string(),
+                entry: "airport: wireguard://***@us.example.com:51820#US".to_string(),
+                error: "Proxy type not supported 'wireguard'".to_string(),
+            }],
+            ..Default::default()
+        };
+        let snapshot = Snapshot::new(config(true), upstreams);
+        assert!(matches!(
+            snapshot.check(|g| g == "US"),
+            Err(Error::InvalidEntries(entries)) if entries[0].entry.starts_with("airport: ")
+        ));
+    }
+
+    #[test]
+    fn test_upstream_names() {
+        let config: Config = toml::from_str(
+            r#"
+            [groups]
+            A = ["trojan://password@a.example.com:443#HK"]
+            B = []
+            "#,
+        )
+        .unwrap();
+        let proxy = |url: &str| Proxy::try_from(url.to_string()).unwrap();
+        let upstreams = Upstreams {
+            proxies: HashMap::from([(
+                "B".to_string(),
+                vec![
+                    proxy("trojan://password@a.example.com:443#HK"),
+                    proxy("trojan://password@b.example.com:443#HK"),
+                    proxy("trojan://password@c.example.com:443#A"),
+                    proxy("trojan://password@d.example.com:443#US"),
+                ],
+            )]),
+            ..Default::default()
+        };
+
+        let snapshot = Snapshot::new(config, upstreams);
+        let names: Vec<&str> = snapshot.proxies["B"].iter().map(Proxy::name).collect();
+        assert_eq!(names, ["HK", "US"]);
+        let failures: Vec<&str> = snapshot.failures.iter().map(|f| f.entry.as_str()).collect();
+        assert_eq!(failures, ["HK", "A"]);
+    }
+
+    #[tokio::test]
+    async fn test_upstreams_merged_into_groups() {
+        use axum::{routing::get, Router};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route(
+            "/sub",
+            get(|| async {
+                (
+                    [(
+                        "subscription-userinfo",
+                        "upload=1; download=2; total=30; expire=200",
+                    )],
+                    "trojan://password@hk.example.com:443#HK\ntrojan://password@jp.example.com:443#JP\nwireguard://key@sg.example.com:51820#SG\n",
+                )
+            }),
+        );
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let path =
+            std::env::temp_dir().join(format!("sub-provider-upstream-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            format!(
+                r#"
+                rules = ["MATCH,up | HK"]
+                [profile]
+                userinfo = {{ upload = 1, total = 10, expire = 300 }}
+                [upstreams.a]
+                url = "http://{addr}/sub"
+                groups = ["group"]
+                prefix = "up | "
+                exclude = "JP"
+                [upstreams.down]
+                url = "http://{addr}/missing"
+                groups = ["group"]
+                [groups]
+                group = ["trojan://password@hostname:443#local"]
+                "#,
+            ),
+        )
+        .unwrap();
+
+        let state = AppState::load(&path, Cache::default()).await.unwrap();
+        let names: Vec<String> = state.snapshot().proxies["group"]
+            .iter()
+            .map(|p| p.name().to_string())
+            .collect();
+        assert_eq!(names, ["local", "up | HK"]);
+        assert_eq!(
+            state.snapshot().userinfo.as_ref().unwrap().to_string(),
+            "upload=2; download=2; total=40; expire=200"
+        );
+        assert_eq!(
+            state.snapshot().failures,
+            [EntryError {
+                group: "group".to_string(),
+                entry: "a: wireguard://***@sg.example.com:51820#SG".to_string(),
+                error: "Proxy type not supported 'wireguard'".to_string(),
+            }]
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
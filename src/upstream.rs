@@ -0,0 +1,763 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use regex::Regex;
+use reqwest::{
+    header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+    StatusCode,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::Error,
+    profile::{Userinfo, SUBSCRIPTION_USERINFO},
+    proxy::Proxy,
+    util::{decode_base64_string, mask_url, now, percent_decode},
+};
+
+pub const DEFAULT_TIMEOUT: u64 = 10;
+pub const DEFAULT_MAX_AGE: u64 = 3600;
+
+/// A remote subscription whose proxies are merged into some of our groups.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct Upstream {
+    pub url: String,
+    /// Groups the proxies of this subscription are appended to.
+    pub groups: Vec<String>,
+    /// Prepended to the name of every proxy, to keep names unique across upstreams.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prefix: Option<String>,
+    /// Regex a proxy name has to match to be kept.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub include: Option<String>,
+    /// Regex a proxy name must not match to be kept, checked after `include`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exclude: Option<String>,
+    /// Request timeout in seconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+    /// Seconds a fetched body is served before it is revalidated.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_age: Option<u64>,
+}
+
+/// A subscription body as last fetched, with the validators to revalidate it.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct Cached {
+    pub url: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /// Raw `subscription-userinfo` header of the last response carrying one.
+    #[serde(default)]
+    pub userinfo: Option<String>,
+    /// Unix timestamp of the last successful fetch or revalidation.
+    pub fetched_at: u64,
+    pub body: String,
+}
+
+/// Last good body of every upstream by name, kept in memory and, when a
+/// directory is set, on disk so a restart does not depend on the upstreams.
+#[derive(Clone, Debug, Default)]
+pub struct Cache {
+    dir: Option<PathBuf>,
+    client: reqwest::Client,
+    entries: Arc<Mutex<HashMap<String, Cached>>>,
+}
+
+/// The proxies of a subscription body and the entries left out of it.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Parsed {
+    pub proxies: Vec<Proxy>,
+    pub skipped: Vec<Skipped>,
+}
+
+/// An entry of a subscription that did not parse (or is not supported).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Skipped {
+    /// Proxy name when the entry has one, checked against the filters.
+    pub name: Option<String>,
+    /// The entry as shown in errors, links have their credentials masked.
+    pub entry: String,
+    pub error: String,
+}
+
+/// The part of a clash profile we read proxies from.
+#[derive(Deserialize)]
+struct ClashProfile {
+    proxies: Vec<serde_yaml::Value>,
+}
+
+impl Upstream {
+    pub fn validate(&self, name: &str) -> Result<(), Error> {
+        self.filters()
+            .map(|_| ())
+            .map_err(|e| Error::InvalidUpstream(name.to_string(), e.to_string()))
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout.unwrap_or(DEFAULT_TIMEOUT))
+    }
+
+    pub fn max_age(&self) -> Duration {
+        Duration::from_secs(self.max_age.unwrap_or(DEFAULT_MAX_AGE))
+    }
+
+    /// Download the subscription, conditionally when there is a cached copy to
+    /// revalidate. A body without any proxy we understand is an error.
+    pub async fn fetch(
+        &self,
+        client: &reqwest::Client,
+        cached: Option<&Cached>,
+    ) -> Result<Cached, Error> {
+        let mut request = client.get(&self.url).timeout(self.timeout());
+        if let Some(cached) = cached {
+            if let Some(etag) = &cached.etag {
+                request = request.header(IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &cached.last_modified {
+                request = request.header(IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+
+        let response = request.send().await?.error_for_status()?;
+        let header = |name: &str| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(String::from)
+        };
+        let userinfo = header(SUBSCRIPTION_USERINFO);
+
+        if let (StatusCode::NOT_MODIFIED, Some(cached)) = (response.status(), cached) {
+            return Ok(Cached {
+                userinfo: userinfo.or(cached.userinfo.clone()),
+                fetched_at: now(),
+                ..cached.clone()
+            });
+        }
+
+        let etag = header(ETAG.as_str());
+        let last_modified = header(LAST_MODIFIED.as_str());
+        let body = response.text().await?;
+
+        if parse(&body)?.proxies.is_empty() {
+            return Err(Error::InvalidUpstream(
+                self.url.clone(),
+                "no proxies".to_string(),
+            ));
+        }
+
+        Ok(Cached {
+            url: self.url.clone(),
+            etag,
+            last_modified,
+            userinfo,
+            fetched_at: now(),
+            body,
+        })
+    }
+
+    /// Proxies of a fetched body, filtered and renamed, and the entries that
+    /// were skipped among the ones the filters would have kept.
+    pub fn proxies(&self, body: &str) -> Result<Parsed, Error> {
+        let Parsed { proxies, skipped } = parse(body)?;
+        let (include, exclude) = self.filters()?;
+
+        Ok(Parsed {
+            proxies: self.apply(proxies)?,
+            skipped: skipped
+                .into_iter()
+                .filter(|s| {
+                    s.name.as_deref().is_none_or(|name| {
+                        include.as_ref().is_none_or(|r| r.is_match(name))
+                            && !exclude.as_ref().is_some_and(|r| r.is_match(name))
+                    })
+                })
+                .collect(),
+        })
+    }
+
+    /// Drop the proxies the filters reject and prefix the names of the rest.
+    pub fn apply(&self, proxies: Vec<Proxy>) -> Result<Vec<Proxy>, Error> {
+        let (include, exclude) = self.filters()?;
+
+        Ok(proxies
+            .into_iter()
+            .filter(|p| include.as_ref().is_none_or(|r| r.is_match(p.name())))
+            .filter(|p| !exclude.as_ref().is_some_and(|r| r.is_match(p.name())))
+            .map(|mut p| {
+                if let (Some(prefix), Some(base)) = (&self.prefix, p.base_mut()) {
+                    base.name = format!("{}{}", prefix, base.name);
+                }
+                p
+            })
+            .collect())
+    }
+
+    fn filters(&self) -> Result<(Option<Regex>, Option<Regex>), Error> {
+        let include = self.include.as_deref().map(Regex::new).transpose()?;
+        let exclude = self.exclude.as_deref().map(Regex::new).transpose()?;
+
+        Ok((include, exclude))
+    }
+}
+
+impl Cached {
+    /// The userinfo header, ignored when the upstream sends something odd.
+    pub fn userinfo(&self) -> Option<Userinfo> {
+        self.userinfo.as_deref().and_then(|u| u.parse().ok())
+    }
+
+    pub fn is_stale(&self, max_age: Duration) -> bool {
+        now().saturating_sub(self.fetched_at) >= max_age.as_secs()
+    }
+}
+
+impl Cache {
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        Cache {
+            dir,
+            ..Default::default()
+        }
+    }
+
+    /// The body of `upstream`, from the cache while it is fresh and fetched
+    /// otherwise. When fetching fails the stale copy is served instead.
+    pub async fn load(&self, name: &str, upstream: &Upstream) -> Result<Cached, Error> {
+        let cached = self.get(name, upstream);
+
+        match cached {
+            Some(cached) if !cached.is_stale(upstream.max_age()) => Ok(cached),
+            cached => match upstream.fetch(&self.client, cached.as_ref()).await {
+                Ok(fetched) => {
+                    self.put(name, fetched.clone());
+                    Ok(fetched)
+                }
+                Err(e) => match cached {
+                    Some(cached) => {
+                        eprintln!(
+                            "failed to refresh upstream {}, serving the cached copy: {}",
+                            name, e
+                        );
+                        Ok(cached)
+                    }
+                    None => Err(e),
+                },
+            },
+        }
+    }
+
+    /// Whether `upstream` has to be fetched on the next snapshot.
+    pub fn is_stale(&self, name: &str, upstream: &Upstream) -> bool {
+        self.get(name, upstream)
+            .is_none_or(|cached| cached.is_stale(upstream.max_age()))
+    }
+
+    // a copy cached for another url does not count
+    fn get(&self, name: &str, upstream: &Upstream) -> Option<Cached> {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if !entries.contains_key(name) {
+            let cached = self
+                .path(name)
+                .and_then(|path| std::fs::read(path).ok())
+                .and_then(|bytes| serde_json::from_slice(&bytes).ok());
+            entries.extend(cached.map(|cached| (name.to_string(), cached)));
+        }
+
+        entries
+            .get(name)
+            .filter(|cached| cached.url == upstream.url)
+            .cloned()
+    }
+
+    fn put(&self, name: &str, cached: Cached) {
+        if let Some(path) = self.path(name) {
+            let written = path
+                .parent()
+                .map_or(Ok(()), std::fs::create_dir_all)
+                .and_then(|_| std::fs::write(&path, serde_json::to_vec(&cached)?));
+            if let Err(e) = written {
+                eprintln!("failed to write cache {}: {}", path.display(), e);
+            }
+        }
+
+        self.entries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(name.to_string(), cached);
+    }
+
+    fn path(&self, name: &str) -> Option<PathBuf> {
+        let file: String = name
+            .chars()
+            .map(|c| match c.is_ascii_alphanumeric() || c == '-' {
+                true => c,
+                false => '_',
+            })
+            .collect();
+
+        self.dir
+            .as_ref()
+            .map(|dir| dir.join(format!("{}.json", file)))
+    }
+}
+
+/// Parse a subscription body, either a clash profile with a `proxies` list,
+/// a base64 encoded link list or a plain one. Entries we can not parse (or do
+/// not support) are skipped and returned alongside.
+pub fn parse(body: &str) -> Result<Parsed, Error> {
+    let mut parsed = Parsed::default();
+
+    if let Ok(profile) = serde_yaml::from_str::<ClashProfile>(body) {
+        for value in profile.proxies {
+            let name = value.get("name").and_then(|n| n.as_str()).map(String::from);
+            match from_clash(value) {
+                Ok(proxy) => parsed.proxies.push(proxy),
+                Err(e) => parsed.skipped.push(Skipped {
+                    entry: name.clone().unwrap_or_else(|| "unnamed proxy".to_string()),
+                    name,
+                    error: e.to_string(),
+                }),
+            }
+        }
+        return Ok(parsed);
+    }
+
+    let links = match decode_base64_string(body) {
+        Ok(decoded) if decoded.contains("://") => decoded,
+        _ => body.to_string(),
+    };
+
+    for link in links.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        match Proxy::try_from(link.to_string()) {
+            Ok(proxy) => parsed.proxies.push(proxy),
+            Err(e) => parsed.skipped.push(Skipped {
+                name: url::Url::parse(link)
+                    .ok()
+                    .and_then(|url| url.fragment().and_then(|f| percent_decode(f).ok())),
+                entry: mask_url(link),
+                error: e.to_string(),
+            }),
+        }
+    }
+
+    Ok(parsed)
+}
+
+// clash has no `tls` table, so the flattened one is always there: trojan and
+// hysteria2 always use tls, vmess and vless only with `tls: true`
+fn from_clash(value: serde_yaml::Value) -> Result<Proxy, serde_yaml::Error> {
+    let mut proxy = serde_yaml::from_value(value)?;
+    match &mut proxy {
+        Proxy::Trojan(trojan) => trojan.tls.iter_mut().for_each(|tls| tls.tls = true),
+        Proxy::Hysteria2(hysteria2) => hysteria2.tls.iter_mut().for_each(|tls| tls.tls = true),
+        Proxy::Vmess(vmess) => vmess.tls = vmess.tls.take().filter(|tls| tls.tls),
+        Proxy::Vless(vless) => vless.tls = vless.tls.take().filter(|tls| tls.tls),
+        _ => {}
+    }
+
+    Ok(proxy)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::protocol::{Network, RealityOpts};
+    use axum::{
+        extract::State,
+        http::HeaderMap,
+        response::{IntoResponse, Response},
+        routing::get,
+        Router,
+    };
+    use base64::{engine::general_purpose::STANDARD, Engine as _};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const LINKS: &str = "trojan://password@hk.example.com:443#HK%2001\n\
+        trojan://password@jp.example.com:443#JP%2001\n\
+        trojan://password@us.example.com:443#US%2001%20expired\n";
+
+    const PROFILE: &str = r#"
+port: 7890
+proxies:
+  - name: SG 01
+    type: ss
+    server: sg.example.com
+    port: 8388
+    cipher: aes-256-gcm
+    password: password
+  - name: SG 02
+    type: trojan
+    server: sg.example.com
+    port: 443
+    password: password
+    sni: sg.example.com
+    skip-cert-verify: true
+  - name: SG 03
+    type: vmess
+    server: sg.example.com
+    port: 443
+    uuid: uuid
+    alterId: 0
+    cipher: auto
+    tls: true
+    servername: cdn.example.com
+    network: ws
+    ws-opts:
+      path: /ws
+  - name: SG 04
+    type: vless
+    server: sg.example.com
+    port: 443
+    uuid: uuid
+    network: tcp
+    tls: true
+    servername: www.example.com
+    client-fingerprint: chrome
+    reality-opts:
+      public-key: public-key
+      short-id: short-id
+  - name: SG 05
+    type: vmess
+    server: sg.example.com
+    port: 80
+    uuid: uuid
+    cipher: auto
+  - name: SG 07
+    type: vless
+    server: sg.example.com
+    port: 443
+    uuid: uuid
+    tls: true
+    network: grpc
+    grpc-opts:
+      grpc-service-name: svc
+  - name: SG 08
+    type: vmess
+    server: sg.example.com
+    port: 80
+    uuid: uuid
+    cipher: auto
+    network: ws
+  - name: SG 06
+    type: ss
+    server: sg.example.com
+    port: 8388
+    cipher: aes-256-gcm
+    password: password
+    plugin: gost-plugin
+    plugin-opts:
+      mode: websocket
+  - name: unknown
+    type: wireguard
+    server: sg.example.com
+    port: 51820
+"#;
+
+    fn upstream(url: String) -> Upstream {
+        Upstream {
+            url,
+            groups: vec!["group".to_string()],
+            prefix: None,
+            include: None,
+            exclude: None,
+            timeout: None,
+            max_age: None,
+        }
+    }
+
+    async fn serve() -> String {
+        let app = Router::new()
+            .route("/base64", get(|| async { STANDARD.encode(LINKS) }))
+            .route("/plain", get(|| async { LINKS }))
+            .route("/clash", get(|| async { PROFILE }))
+            .route(
+                "/slow",
+                get(|| async {
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                    LINKS
+                }),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        format!("http://{}", addr)
+    }
+
+    #[test]
+    fn test_parse() {
+        let names = |body: &str| -> Vec<String> {
+            parse(body)
+                .unwrap()
+                .proxies
+                .iter()
+                .map(|p| p.name().to_string())
+                .collect()
+        };
+
+        assert_eq!(names(LINKS), ["HK 01", "JP 01", "US 01 expired"]);
+        assert_eq!(names(&STANDARD.encode(LINKS)), names(LINKS));
+        assert_eq!(
+            names(PROFILE),
+            ["SG 01", "SG 02", "SG 03", "SG 04", "SG 05", "SG 07", "SG 08"]
+        );
+        assert!(names("").is_empty());
+    }
+
+    #[test]
+    fn test_skipped() {
+        let body = format!("{}wireguard://key@hk.example.com:51820#HK%2002\n", LINKS);
+        let upstream = Upstream {
+            exclude: Some("US".to_string()),
+            ..upstream(String::new())
+        };
+
+        let parsed = upstream.proxies(&body).unwrap();
+        assert_eq!(parsed.proxies.len(), 2);
+        assert_eq!(
+            parsed.skipped,
+            [Skipped {
+                name: Some("HK 02".to_string()),
+                entry: "wireguard://***@hk.example.com:51820#HK%2002".to_string(),
+                error: "Proxy type not supported 'wireguard'".to_string(),
+            }]
+        );
+
+        let upstream = Upstream {
+            exclude: Some("HK".to_string()),
+            ..upstream
+        };
+        assert!(upstream.proxies(&body).unwrap().skipped.is_empty());
+    }
+
+    #[test]
+    fn test_parse_tls() {
+        let proxies = parse(PROFILE).unwrap().proxies;
+
+        let Proxy::Trojan(trojan) = &proxies[1] else {
+            panic!("expected trojan, got {:?}", proxies[1]);
+        };
+        let tls = trojan.tls.as_ref().unwrap();
+        assert!(tls.tls);
+        assert_eq!(tls.server_name.as_deref(), Some("sg.example.com"));
+        assert!(tls.skip_cert_verify);
+
+        let Proxy::Vmess(vmess) = &proxies[2] else {
+            panic!("expected vmess, got {:?}", proxies[2]);
+        };
+        let tls = vmess.tls.as_ref().unwrap();
+        assert!(tls.tls);
+        assert_eq!(tls.server_name.as_deref(), Some("cdn.example.com"));
+        assert!(matches!(vmess.network, Some(Network::Ws { ref path, .. }) if path == "/ws"));
+
+        let Proxy::Vless(vless) = &proxies[3] else {
+            panic!("expected vless, got {:?}", proxies[3]);
+        };
+        let tls = vless.tls.as_ref().unwrap();
+        assert_eq!(tls.server_name.as_deref(), Some("www.example.com"));
+        assert_eq!(tls.client_fingerprint.as_deref(), Some("chrome"));
+        assert_eq!(
+            tls.reality_opts,
+            Some(RealityOpts {
+                public_key: "public-key".to_string(),
+                short_id: "short-id".to_string(),
+            })
+        );
+
+        let Proxy::Vmess(vmess) = &proxies[4] else {
+            panic!("expected vmess, got {:?}", proxies[4]);
+        };
+        assert!(vmess.tls.is_none());
+    }
+
+    #[test]
+    fn test_parse_network() {
+        let proxies = parse(PROFILE).unwrap().proxies;
+
+        let Proxy::Vless(vless) = &proxies[5] else {
+            panic!("expected vless, got {:?}", proxies[5]);
+        };
+        assert_eq!(
+            vless.network,
+            Some(Network::Grpc {
+                grpc_service_name: Some("svc".to_string())
+            })
+        );
+
+        // without ws-opts clash connects to `/`
+        let Proxy::Vmess(vmess) = &proxies[6] else {
+            panic!("expected vmess, got {:?}", proxies[6]);
+        };
+        assert!(matches!(vmess.network, Some(Network::Ws { ref path, .. }) if path == "/"));
+
+        // and the same keys are written back
+        let yaml = serde_yaml::to_string(&proxies[5]).unwrap();
+        assert!(yaml.contains("grpc-service-name: svc"), "{}", yaml);
+    }
+
+    #[test]
+    fn test_apply() {
+        let upstream = Upstream {
+            prefix: Some("A | ".to_string()),
+            include: Some("HK|US".to_string()),
+            exclude: Some("(?i)expired".to_string()),
+            ..upstream(String::new())
+        };
+
+        let proxies = upstream.apply(parse(LINKS).unwrap().proxies).unwrap();
+        assert_eq!(proxies.len(), 1);
+        assert_eq!(proxies[0].name(), "A | HK 01");
+
+        let upstream = Upstream {
+            include: Some("(".to_string()),
+            ..upstream
+        };
+        assert!(upstream.validate("a").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fetch() {
+        let base = serve().await;
+        let client = reqwest::Client::new();
+        let fetch = |upstream: Upstream| {
+            let client = client.clone();
+            async move {
+                let cached = upstream.fetch(&client, None).await?;
+                upstream.proxies(&cached.body)
+            }
+        };
+
+        for path in ["base64", "plain"] {
+            let parsed = fetch(upstream(format!("{}/{}", base, path))).await.unwrap();
+            assert_eq!(parsed.proxies.len(), 3, "{}", path);
+            assert!(parsed.skipped.is_empty(), "{}", path);
+        }
+
+        let parsed = fetch(upstream(format!("{}/clash", base))).await.unwrap();
+        assert_eq!(parsed.proxies.len(), 7);
+        let skipped: Vec<&str> = parsed.skipped.iter().map(|s| s.entry.as_str()).collect();
+        assert_eq!(skipped, ["SG 06", "unknown"]);
+        assert!(parsed.skipped[0].error.contains("gost-plugin"));
+
+        assert!(fetch(upstream(format!("{}/missing", base))).await.is_err());
+
+        let slow = Upstream {
+            timeout: Some(1),
+            ..upstream(format!("{}/slow", base))
+        };
+        assert!(matches!(fetch(slow).await, Err(Error::Http(_))));
+    }
+
+    #[derive(Default)]
+    struct Flaky {
+        // 0 serves the links, 1 fails, 2 serves garbage
+        mode: AtomicUsize,
+        fetched: AtomicUsize,
+        not_modified: AtomicUsize,
+    }
+
+    async fn flaky(State(flaky): State<Arc<Flaky>>, headers: HeaderMap) -> Response {
+        match flaky.mode.load(Ordering::SeqCst) {
+            1 => return StatusCode::BAD_GATEWAY.into_response(),
+            2 => return "<html>oops</html>".into_response(),
+            _ => {}
+        }
+        if headers.get(IF_NONE_MATCH).is_some_and(|v| v == "\"v1\"") {
+            flaky.not_modified.fetch_add(1, Ordering::SeqCst);
+            return StatusCode::NOT_MODIFIED.into_response();
+        }
+
+        flaky.fetched.fetch_add(1, Ordering::SeqCst);
+        (
+            [
+                (ETAG.as_str(), "\"v1\""),
+                (SUBSCRIPTION_USERINFO, "upload=1; download=2; total=3"),
+            ],
+            LINKS,
+        )
+            .into_response()
+    }
+
+    #[tokio::test]
+    async fn test_cache() {
+        let state = Arc::new(Flaky::default());
+        let app = Router::new()
+            .route("/sub", get(flaky))
+            .with_state(state.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let dir = std::env::temp_dir().join(format!("sub-provider-cache-{}", std::process::id()));
+        let proxies = |cache: Cache, upstream: Upstream| async move {
+            let cached = cache.load("a", &upstream).await?;
+            upstream.proxies(&cached.body).map(|parsed| parsed.proxies)
+        };
+        let fresh = upstream(format!("http://{}/sub", addr));
+        let stale = Upstream {
+            max_age: Some(0),
+            ..fresh.clone()
+        };
+
+        // fetched once, then served from memory while fresh
+        let cache = Cache::new(Some(dir.clone()));
+        assert!(cache.is_stale("a", &fresh));
+        assert_eq!(
+            proxies(cache.clone(), fresh.clone()).await.unwrap().len(),
+            3
+        );
+        assert_eq!(
+            proxies(cache.clone(), fresh.clone()).await.unwrap().len(),
+            3
+        );
+        assert_eq!(state.fetched.load(Ordering::SeqCst), 1);
+        assert!(!cache.is_stale("a", &fresh));
+
+        // a new cache reads the body back from disk and revalidates it
+        let cache = Cache::new(Some(dir.clone()));
+        assert_eq!(
+            proxies(cache.clone(), stale.clone()).await.unwrap().len(),
+            3
+        );
+        assert_eq!(state.fetched.load(Ordering::SeqCst), 1);
+        assert_eq!(state.not_modified.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            cache
+                .load("a", &stale)
+                .await
+                .unwrap()
+                .userinfo()
+                .unwrap()
+                .total,
+            3
+        );
+
+        // upstream down or serving garbage, the last good copy is kept
+        for mode in [1, 2] {
+            state.mode.store(mode, Ordering::SeqCst);
+            assert_eq!(
+                proxies(cache.clone(), stale.clone()).await.unwrap().len(),
+                3
+            );
+        }
+        assert!(proxies(Cache::default(), stale.clone()).await.is_err());
+
+        // a copy cached for another url is not used
+        let moved = Upstream {
+            url: format!("http://{}/moved", addr),
+            ..stale
+        };
+        assert!(cache.is_stale("a", &moved));
+        assert!(proxies(cache.clone(), moved.clone()).await.is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
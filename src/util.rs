@@ -1,5 +1,8 @@
 use std::collections::HashMap;
 
+use crate::error::Error;
+use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine as _};
+
 pub fn is_false(b: &bool) -> bool {
     !b
 }
@@ -7,5 +10,122 @@ pub fn is_false(b: &bool) -> bool {
 pub fn get_query(key: &str, url: &url::Url) -> Option<String> {
     let hash_query: HashMap<_, _> = url.query_pairs().into_owned().collect();
 
-    hash_query.get(key).map(|s| String::from(s))
+    hash_query.get(key).map(String::from)
+}
+
+/// Seconds since the unix epoch.
+pub fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+pub fn percent_decode(s: &str) -> Result<String, Error> {
+    Ok(percent_encoding::percent_decode_str(s)
+        .decode_utf8()
+        .map_err(Error::Utf8)?
+        .into_owned())
+}
+
+/// Hide the credentials of a share link for error messages: the userinfo and
+/// query are dropped, and links without a userinfo (vmess, legacy ss) carry
+/// everything base64 encoded in place of the host, so only the name is kept.
+pub fn mask_url(s: &str) -> String {
+    let Ok(url) = url::Url::parse(s) else {
+        return "***".to_string();
+    };
+    let fragment = url
+        .fragment()
+        .map(|f| format!("#{}", f))
+        .unwrap_or_default();
+
+    match (url.username().is_empty(), url.host_str()) {
+        (false, Some(host)) => {
+            let port = url.port().map(|p| format!(":{}", p)).unwrap_or_default();
+            format!("{}://***@{}{}{}", url.scheme(), host, port, fragment)
+        }
+        _ => format!("{}://***{}", url.scheme(), fragment),
+    }
+}
+
+/// Decode base64 regardless of alphabet (standard or URL-safe) and padding,
+/// as share links and subscriptions in the wild use all four variants.
+pub fn decode_base64(input: &str) -> Result<Vec<u8>, Error> {
+    let normalized: String = input
+        .trim()
+        .trim_end_matches('=')
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| match c {
+            '-' => '+',
+            '_' => '/',
+            c => c,
+        })
+        .collect();
+
+    STANDARD_NO_PAD
+        .decode(normalized.as_bytes())
+        .map_err(Error::Base64)
+}
+
+pub fn decode_base64_string(input: &str) -> Result<String, Error> {
+    String::from_utf8(decode_base64(input)?).map_err(|e| Error::Utf8(e.utf8_error()))
+}
+
+/// Deep-merge `overlay` into `base`: mappings are merged key by key, sequences
+/// are concatenated (overlay first) and any other value from `overlay` wins.
+pub fn merge_yaml(base: &mut serde_yaml::Value, overlay: serde_yaml::Value) {
+    use serde_yaml::Value;
+
+    match (base, overlay) {
+        (Value::Mapping(base), Value::Mapping(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_yaml(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (Value::Sequence(base), Value::Sequence(overlay)) => {
+            base.splice(0..0, overlay);
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mask_url() {
+        assert_eq!(
+            mask_url("trojan://password@hk.example.com:8443?sni=a#HK%2001"),
+            "trojan://***@hk.example.com:8443#HK%2001"
+        );
+        assert_eq!(mask_url("vmess://eyJhZGQiOiJ4In0="), "vmess://***");
+        assert_eq!(mask_url("not a url"), "***");
+    }
+
+    #[test]
+    fn test_merge_yaml() {
+        let mut base: serde_yaml::Value =
+            serde_yaml::from_str("port: 7890\nrules: ['MATCH,DIRECT']\ndns: {enable: false}\n")
+                .unwrap();
+        let overlay: serde_yaml::Value = serde_yaml::from_str(
+            "port: 7892\nrules: ['GEOIP,CN,DIRECT']\ndns: {enable: true, ipv6: false}\ntun: {stack: system}\n",
+        )
+        .unwrap();
+
+        merge_yaml(&mut base, overlay);
+
+        let expected: serde_yaml::Value = serde_yaml::from_str(
+            "port: 7892\nrules: ['GEOIP,CN,DIRECT', 'MATCH,DIRECT']\ndns: {enable: true, ipv6: false}\ntun: {stack: system}\n",
+        )
+        .unwrap();
+        assert_eq!(base, expected);
+    }
 }